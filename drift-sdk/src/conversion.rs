@@ -1,6 +1,47 @@
-pub fn convert_to_number(big_number: u64, precision: u128) -> f64 {
+#[cfg(test)]
+mod tests;
+
+/// Basis points in 100%.
+pub const BPS_PRECISION: i128 = 10_000;
+
+pub fn convert_to_number<T: Into<i128>>(big_number: T, precision: u128) -> f64 {
+    convert_to_number_i128(big_number.into(), precision)
+}
+
+/// `convert_to_number` for signed values such as oracle offsets and effective prices,
+/// keeping the sign.
+pub fn convert_to_number_i128(big_number: i128, precision: u128) -> f64 {
     if big_number == 0 {
         return 0.0;
     }
     big_number as f64 / precision as f64
 }
+
+/// Difference of `a` relative to `b` in basis points, rounded half away from zero and
+/// saturated to the i32 range. Returns 0 when `b` is 0.
+pub fn price_to_bps_diff(a: i128, b: i128) -> i32 {
+    if b == 0 {
+        return 0;
+    }
+
+    let bps = div_round(a.saturating_sub(b).saturating_mul(BPS_PRECISION), b);
+    bps.clamp(i32::MIN as i128, i32::MAX as i128) as i32
+}
+
+/// `price` moved by `bps` basis points, rounded half away from zero.
+pub fn apply_bps(price: i128, bps: i32) -> i128 {
+    div_round(
+        price.saturating_mul(BPS_PRECISION + bps as i128),
+        BPS_PRECISION,
+    )
+}
+
+fn div_round(numerator: i128, denominator: i128) -> i128 {
+    let half = denominator.abs() / 2;
+    let magnitude = numerator.saturating_abs().saturating_add(half) / denominator.abs();
+    if (numerator < 0) != (denominator < 0) {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
@@ -0,0 +1,53 @@
+use crate::conversion::{apply_bps, convert_to_number, convert_to_number_i128, price_to_bps_diff};
+
+#[test]
+fn price_to_bps_diff_signs() {
+    assert_eq!(price_to_bps_diff(101_000_000, 100_000_000), 100);
+    assert_eq!(price_to_bps_diff(99_000_000, 100_000_000), -100);
+    assert_eq!(price_to_bps_diff(100_000_000, 100_000_000), 0);
+    assert_eq!(price_to_bps_diff(100_000_000, 0), 0);
+}
+
+#[test]
+fn price_to_bps_diff_rounds_half_away_from_zero() {
+    // 0.5bp and 0.4bp either side of 1_000_000
+    assert_eq!(price_to_bps_diff(1_000_050, 1_000_000), 1);
+    assert_eq!(price_to_bps_diff(1_000_040, 1_000_000), 0);
+    assert_eq!(price_to_bps_diff(999_950, 1_000_000), -1);
+    assert_eq!(price_to_bps_diff(999_960, 1_000_000), 0);
+}
+
+#[test]
+fn price_to_bps_diff_saturates() {
+    assert_eq!(price_to_bps_diff(i128::MAX / 2, 1), i32::MAX);
+}
+
+#[test]
+fn apply_bps_moves_price() {
+    assert_eq!(apply_bps(100_000_000, 25), 100_250_000);
+    assert_eq!(apply_bps(100_000_000, -25), 99_750_000);
+    assert_eq!(apply_bps(100_000_000, 0), 100_000_000);
+}
+
+#[test]
+fn apply_bps_rounds_half_away_from_zero() {
+    assert_eq!(apply_bps(50, 1), 50);
+    assert_eq!(apply_bps(5_000, 1), 5_001);
+    assert_eq!(apply_bps(15_000, 1), 15_002);
+    assert_eq!(apply_bps(-15_000, 1), -15_002);
+}
+
+#[test]
+fn convert_to_number_keeps_sign() {
+    assert_eq!(convert_to_number_i128(-2_500_000, 1_000_000), -2.5);
+    assert_eq!(convert_to_number_i128(2_500_000, 1_000_000), 2.5);
+    assert_eq!(convert_to_number(-250_000_i32, 1_000_000), -0.25);
+    assert_eq!(convert_to_number(2_500_000_u64, 1_000_000), 2.5);
+}
+
+#[test]
+fn convert_to_number_short_circuits_zero() {
+    assert_eq!(convert_to_number_i128(0, 1_000_000), 0.0);
+    assert_eq!(convert_to_number(0_u64, 0), 0.0);
+    assert_eq!(convert_to_number_i128(0, 0), 0.0);
+}
@@ -1,30 +1,74 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anchor_lang::prelude::Pubkey;
+use anchor_lang::prelude::{msg, Pubkey};
 use drift::{
     controller::position::PositionDirection,
-    error::DriftResult,
+    error::{DriftResult, ErrorCode},
+    math::{
+        constants::BASE_PRECISION_I128,
+        orders::{order_satisfies_trigger_condition, standardize_price},
+    },
     state::{
-        events::OrderRecord,
+        events::{OrderAction, OrderActionRecord, OrderRecord},
+        oracle::OraclePriceData,
+        perp_market::AMM,
         user::{Order, OrderStatus, OrderTriggerCondition, OrderType},
         user_map::UserMap,
     },
+    validate,
 };
 
 use crate::{
-    dlob_node::DLOBNodeType,
-    dlob_orders::DLOBOrders,
+    conversion::apply_bps,
+    dlob_node::{DLOBNode, DLOBNodeType, DefaultNodeFactory, NodeFactory, VAMMNode},
+    dlob_orders::{DLOBOrder, DLOBOrders},
+    frozen_dlob::FrozenDlob,
+    l2::{accumulate_levels, aggregate_levels, L2Level, L2Options, L2OrderBook},
+    l3::{L3Order, L3OrderBook},
     node_list::{get_order_signature, NodeList, SortDirection},
 };
 
+#[cfg(test)]
+mod tests;
+
+/// Upper bound on market indexes accepted by `insert_order_validated`; anything above is
+/// treated as a corrupt event rather than a real market.
+pub const MAX_PLAUSIBLE_MARKET_INDEX: u16 = 1024;
+
+/// Slots of recent fills `DLOB::estimate_time_to_fill` measures a market's fill rate over.
+pub const FILL_RATE_WINDOW_SLOTS: u64 = 150;
+
+/// Nominal slot time used to turn slots into wall-clock estimates.
+const SLOT_DURATION_MS: u64 = 400;
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Side {
     Bid,
     Ask,
 }
 
+impl Side {
+    pub fn from_direction(direction: PositionDirection) -> Self {
+        match direction {
+            PositionDirection::Long => Side::Bid,
+            PositionDirection::Short => Side::Ask,
+        }
+    }
+
+    pub fn to_direction(self) -> PositionDirection {
+        match self {
+            Side::Bid => PositionDirection::Long,
+            Side::Ask => PositionDirection::Short,
+        }
+    }
+}
+
 // custom enum because the original doesn't impl Hash
-#[derive(Clone, Copy, Hash, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MarketType {
     Spot,
     Perp,
@@ -39,12 +83,37 @@ impl From<drift::state::user::MarketType> for MarketType {
     }
 }
 
-pub enum MarketNodeLists {
-    RestingLimit(SideNodeList),
-    FloatingLimit(SideNodeList),
-    TakingLimit(SideNodeList),
-    Market(SideNodeList),
-    Trigger(TriggerNodeList),
+impl From<MarketType> for drift::state::user::MarketType {
+    fn from(market_type: MarketType) -> Self {
+        match market_type {
+            MarketType::Spot => drift::state::user::MarketType::Spot,
+            MarketType::Perp => drift::state::user::MarketType::Perp,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct MarketId {
+    pub market_index: u16,
+    pub market_type: MarketType,
+}
+
+impl MarketId {
+    pub fn new(market_index: u16, market_type: MarketType) -> Self {
+        Self {
+            market_index,
+            market_type,
+        }
+    }
+}
+
+/// Every node list of one market.
+pub struct MarketNodeLists {
+    pub resting_limit: SideNodeList,
+    pub floating_limit: SideNodeList,
+    pub taking_limit: SideNodeList,
+    pub market: SideNodeList,
+    pub trigger: TriggerNodeList,
 }
 
 #[derive(Debug, Clone)]
@@ -58,18 +127,275 @@ pub struct TriggerNodeList {
     pub below: NodeList,
 }
 
+impl MarketNodeLists {
+    /// The bid and ask lists for `node_type`, `None` for the trigger lists.
+    pub fn side_lists(&self, node_type: DLOBNodeType) -> Option<&SideNodeList> {
+        match node_type {
+            DLOBNodeType::RestingLimit => Some(&self.resting_limit),
+            DLOBNodeType::FloatingLimit => Some(&self.floating_limit),
+            DLOBNodeType::TakingLimit => Some(&self.taking_limit),
+            DLOBNodeType::Market => Some(&self.market),
+            DLOBNodeType::Trigger => None,
+        }
+    }
+
+    fn list(&self, node_type: DLOBNodeType, order_sub_type: OrderSubType) -> Option<&NodeList> {
+        match order_sub_type {
+            OrderSubType::Side(side) => self.side_lists(node_type).map(|list| match side {
+                Side::Ask => &list.ask,
+                Side::Bid => &list.bid,
+            }),
+            OrderSubType::Trigger(OrderTriggerCondition::Above) => Some(&self.trigger.above),
+            OrderSubType::Trigger(OrderTriggerCondition::Below) => Some(&self.trigger.below),
+            OrderSubType::Trigger(_) => None,
+        }
+    }
+
+    fn list_mut(
+        &mut self,
+        node_type: DLOBNodeType,
+        order_sub_type: OrderSubType,
+    ) -> Option<&mut NodeList> {
+        let side_lists = match node_type {
+            DLOBNodeType::RestingLimit => &mut self.resting_limit,
+            DLOBNodeType::FloatingLimit => &mut self.floating_limit,
+            DLOBNodeType::TakingLimit => &mut self.taking_limit,
+            DLOBNodeType::Market => &mut self.market,
+            DLOBNodeType::Trigger => {
+                return match order_sub_type {
+                    OrderSubType::Trigger(OrderTriggerCondition::Above) => {
+                        Some(&mut self.trigger.above)
+                    }
+                    OrderSubType::Trigger(OrderTriggerCondition::Below) => {
+                        Some(&mut self.trigger.below)
+                    }
+                    _ => None,
+                }
+            }
+        };
+
+        match order_sub_type {
+            OrderSubType::Side(Side::Ask) => Some(&mut side_lists.ask),
+            OrderSubType::Side(Side::Bid) => Some(&mut side_lists.bid),
+            OrderSubType::Trigger(_) => None,
+        }
+    }
+
+    /// Every list, labelled with the node type and side (or trigger condition) it holds.
+    fn labeled_lists(&self) -> Vec<(DLOBNodeType, OrderSubType, &NodeList)> {
+        let mut labeled_lists = Vec::with_capacity(10);
+        for (node_type, list) in [
+            (DLOBNodeType::RestingLimit, &self.resting_limit),
+            (DLOBNodeType::FloatingLimit, &self.floating_limit),
+            (DLOBNodeType::TakingLimit, &self.taking_limit),
+            (DLOBNodeType::Market, &self.market),
+        ] {
+            labeled_lists.push((node_type.clone(), OrderSubType::Side(Side::Ask), &list.ask));
+            labeled_lists.push((node_type, OrderSubType::Side(Side::Bid), &list.bid));
+        }
+        labeled_lists.push((
+            DLOBNodeType::Trigger,
+            OrderSubType::Trigger(OrderTriggerCondition::Above),
+            &self.trigger.above,
+        ));
+        labeled_lists.push((
+            DLOBNodeType::Trigger,
+            OrderSubType::Trigger(OrderTriggerCondition::Below),
+            &self.trigger.below,
+        ));
+        labeled_lists
+    }
+
+    fn lists_mut(&mut self) -> [&mut NodeList; 10] {
+        [
+            &mut self.resting_limit.ask,
+            &mut self.resting_limit.bid,
+            &mut self.floating_limit.ask,
+            &mut self.floating_limit.bid,
+            &mut self.taking_limit.ask,
+            &mut self.taking_limit.bid,
+            &mut self.market.ask,
+            &mut self.market.bid,
+            &mut self.trigger.above,
+            &mut self.trigger.below,
+        ]
+    }
+}
+
+/// Where `DLOB::get_mid` takes its mid price from.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MidSource {
+    /// Midpoint of the best resting bid and ask.
+    Book,
+    /// The oracle price, regardless of the book.
+    Oracle,
+    /// Best bid and ask weighted by the size resting on the opposite side.
+    Weighted,
+}
+
+/// Which makers count toward the tradeable book.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MakerMode {
+    /// Every resting maker order.
+    All,
+    /// Only orders from makers registered with `DLOB::add_protected_maker`, for markets in
+    /// protected-maker-only mode.
+    ProtectedOnly,
+}
+
+/// Which oracle price floating (oracle offset) orders are priced off.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OraclePriceSource {
+    /// The current oracle price.
+    Spot,
+    /// A caller-provided oracle TWAP, in PRICE_PRECISION.
+    Twap(i64),
+}
+
+impl OraclePriceSource {
+    /// The oracle data to price nodes with: `oracle_price_data` with its price swapped for
+    /// the TWAP when one is selected.
+    pub fn resolve(self, oracle_price_data: &OraclePriceData) -> OraclePriceData {
+        match self {
+            OraclePriceSource::Spot => *oracle_price_data,
+            OraclePriceSource::Twap(twap_price) => OraclePriceData {
+                price: twap_price,
+                ..*oracle_price_data
+            },
+        }
+    }
+}
+
+/// What reads that aren't handed an oracle price, such as `get_l3_snapshot`, fall back to.
+/// They all go through `DLOB::resolve_oracle_price`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PriceFallback {
+    /// Use the last oracle price recorded with `DLOB::update_oracle_price`.
+    LastKnown,
+    /// Resolve to `OracleNotFound`. `get_l3_snapshot` then leaves floating orders out.
+    RejectQuery,
+}
+
+/// What `insert_order` does with an order whose price isn't a multiple of its market's
+/// tick size.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TickSizePolicy {
+    /// Fail the insert with `InvalidOrder`.
+    Reject,
+    /// Round the price to the tick, down for bids and up for asks, and insert it.
+    Round,
+}
+
+/// Book mid at the time an order was placed, see `DLOB::record_placement_mid`.
+#[cfg(feature = "analytics")]
+#[derive(Debug, Clone, Copy)]
+struct PlacementMid {
+    market_id: MarketId,
+    side: Side,
+    mid: i128,
+}
+
+/// Takers that can be filled right now, split by what they fill against so a router can
+/// send the matching instruction for each.
+#[derive(Debug, Default, Clone)]
+pub struct FillRoute {
+    /// (taker, maker) pairs where the taker crosses a resting maker order.
+    pub against_makers: Vec<(Arc<dyn DLOBNode>, Arc<dyn DLOBNode>)>,
+    /// Takers that cross no maker but do cross the vAMM fallback price.
+    pub against_vamm: Vec<Arc<dyn DLOBNode>>,
+}
+
+/// One side of the vAMM as a `can_fill` fallback: its price and the base it can absorb
+/// there.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FallbackQuote {
+    pub price: i128,
+    pub base_asset_amount: u64,
+}
+
+/// A taking order together with the makers it would be filled against, best first.
+#[derive(Debug, Clone)]
+pub struct NodeToFill {
+    pub node: Arc<dyn DLOBNode>,
+    pub maker_nodes: Vec<Arc<dyn DLOBNode>>,
+}
+
+/// Plain-data copy of every order resting in a single market, used to sync
+/// replicas one market at a time.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MarketSnapshot {
+    pub market_index: u16,
+    pub market_type: MarketType,
+    pub orders: DLOBOrders,
+}
+
+/// Where `DLOB::preview_insert` would file an order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsertPreview {
+    pub node_type: DLOBNodeType,
+    pub side: Side,
+    /// Orders that would stay ahead of it in its list.
+    pub rank: usize,
+    /// Its effective price at the previewed slot.
+    pub price: i128,
+}
+
+/// Best bid and ask under one hypothetical oracle price, see `DLOB::top_of_book_scenarios`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopOfBook {
+    pub oracle_price: i128,
+    pub best_bid: Option<i128>,
+    pub best_ask: Option<i128>,
+}
+
+/// Called by `DLOB::update_order` for every fill it applies with the order signature, the
+/// newly filled base, the order's limit price as a fill price estimate, and the slot.
+pub type FillRecorder = Box<dyn FnMut(&str, u64, i128, u64)>;
+
 pub struct DLOB {
-    open_orders: HashMap<MarketType, HashSet<String>>,
+    /// Open order signatures per market type, ordered so dumps and diffs are reproducible.
+    open_orders: HashMap<MarketType, BTreeSet<String>>,
     order_lists: HashMap<MarketType, HashMap<u16, MarketNodeLists>>,
-    max_slot_for_resting_limit_orders: u32,
+    max_slot_for_resting_limit_orders: u64,
+    /// Highest slot any mutation has been applied at. Slots are monotonic: a mutation
+    /// arriving with an older slot (e.g. a late event after a reconnect) is applied at this
+    /// slot instead, so orders that already aged into the resting lists never regress.
+    latest_slot: u64,
+    node_factory: Arc<dyn NodeFactory>,
+    /// Signatures of open orders keyed by their `max_ts`, so expiry only visits orders whose
+    /// time has passed. Entries for orders removed some other way are skipped on expiry.
+    expiry_buckets: BTreeMap<i64, HashSet<String>>,
+    /// Makers whose orders still count when a market is in protected-maker-only mode.
+    protected_makers: HashSet<Pubkey>,
+    /// Inserts of orders whose oracle offset exceeds this in absolute terms are rejected as
+    /// corrupt. Unbounded when `None`.
+    max_abs_oracle_offset: Option<u32>,
+    /// How far from the oracle, in MARGIN_PRECISION, a market taker may be filled by
+    /// `find_nodes_to_fill`. Unbounded when `None`.
+    market_price_band: Option<u32>,
+    /// Tick size per market, checked on insert according to `tick_size_policy`.
+    tick_sizes: HashMap<MarketId, u64>,
+    tick_size_policy: TickSizePolicy,
+    price_fallback: PriceFallback,
+    fill_recorder: Option<FillRecorder>,
+    /// Base filled per slot within the last `FILL_RATE_WINDOW_SLOTS`, oldest first.
+    recent_fills: HashMap<MarketId, VecDeque<(u64, u64)>>,
+    /// Perp market AMMs quoting alongside the book, keyed by market index.
+    vamms: HashMap<u16, AMM>,
+    /// Last oracle price seen per market, used by `PriceFallback::LastKnown`.
+    last_oracle_prices: HashMap<MarketId, OraclePriceData>,
+    /// Book mid recorded when each order was placed, keyed by order signature.
+    #[cfg(feature = "analytics")]
+    placement_mids: HashMap<String, PlacementMid>,
     initialized: bool,
 }
 
 impl Default for DLOB {
     fn default() -> Self {
         let mut open_orders = HashMap::new();
-        open_orders.insert(MarketType::Perp, HashSet::new());
-        open_orders.insert(MarketType::Spot, HashSet::new());
+        open_orders.insert(MarketType::Perp, BTreeSet::new());
+        open_orders.insert(MarketType::Spot, BTreeSet::new());
 
         let mut order_lists = HashMap::new();
         order_lists.insert(MarketType::Perp, HashMap::new());
@@ -79,6 +405,21 @@ impl Default for DLOB {
             open_orders,
             order_lists,
             max_slot_for_resting_limit_orders: 0,
+            latest_slot: 0,
+            node_factory: Arc::new(DefaultNodeFactory),
+            expiry_buckets: BTreeMap::new(),
+            protected_makers: HashSet::new(),
+            max_abs_oracle_offset: None,
+            market_price_band: None,
+            tick_sizes: HashMap::new(),
+            tick_size_policy: TickSizePolicy::Reject,
+            price_fallback: PriceFallback::RejectQuery,
+            fill_recorder: None,
+            recent_fills: HashMap::new(),
+            vamms: HashMap::new(),
+            last_oracle_prices: HashMap::new(),
+            #[cfg(feature = "analytics")]
+            placement_mids: HashMap::new(),
             initialized: false,
         }
     }
@@ -89,6 +430,21 @@ impl DLOB {
         Ok(DLOB::default())
     }
 
+    /// Creates an empty book whose node lists build their nodes with `node_factory`.
+    pub fn with_node_factory(node_factory: Arc<dyn NodeFactory>) -> DriftResult<Self> {
+        Ok(DLOB {
+            node_factory,
+            ..DLOB::default()
+        })
+    }
+
+    /// Builds a fresh, initialized book from every order in `user_map`.
+    pub fn from_user_map(user_map: &UserMap, slot: u64) -> DriftResult<DLOB> {
+        let mut dlob = DLOB::new()?;
+        dlob.init_from_user_map(user_map, slot)?;
+        Ok(dlob)
+    }
+
     pub fn initialize(&mut self) -> DriftResult<()> {
         self.initialized = true;
         Ok(())
@@ -103,37 +459,157 @@ impl DLOB {
         for market_type in self.order_lists.keys().cloned().collect::<Vec<_>>() {
             if let Some(market_node_lists_map) = self.order_lists.get_mut(&market_type) {
                 for market_node_lists in market_node_lists_map.values_mut() {
-                    match market_node_lists {
-                        MarketNodeLists::RestingLimit(side_node_list)
-                        | MarketNodeLists::FloatingLimit(side_node_list)
-                        | MarketNodeLists::TakingLimit(side_node_list)
-                        | MarketNodeLists::Market(side_node_list) => {
-                            side_node_list.ask.clear();
-                            side_node_list.bid.clear();
-                        }
-                        MarketNodeLists::Trigger(trigger_node_list) => {
-                            trigger_node_list.above.clear();
-                            trigger_node_list.below.clear();
-                        }
-                    }
+                    clear_market_node_lists(market_node_lists);
                 }
             }
         }
         self.order_lists.clear();
 
         self.max_slot_for_resting_limit_orders = 0;
+        self.latest_slot = 0;
+        self.expiry_buckets.clear();
+        #[cfg(feature = "analytics")]
+        self.placement_mids.clear();
 
         self.initialize()?;
 
         Ok(())
     }
 
-    //TODO
-    fn init_from_user_map(&mut self, user_map: UserMap, slot: u64) -> DriftResult<bool> {
+    /// Empties a single market's lists and forgets its open orders, leaving every other
+    /// market untouched.
+    pub fn clear_market(&mut self, market_index: u16, market_type: MarketType) {
+        let order_signatures: HashSet<String> = self
+            .order_signatures(market_index, market_type)
+            .into_iter()
+            .collect();
+        if let Some(open_orders) = self.open_orders.get_mut(&market_type) {
+            for order_signature in &order_signatures {
+                open_orders.remove(order_signature);
+            }
+        }
+        self.expiry_buckets.retain(|_, bucket| {
+            bucket.retain(|order_signature| !order_signatures.contains(order_signature));
+            !bucket.is_empty()
+        });
+
+        if let Some(market_node_lists) = self
+            .order_lists
+            .get_mut(&market_type)
+            .and_then(|market_node_lists_map| market_node_lists_map.get_mut(&market_index))
+        {
+            clear_market_node_lists(market_node_lists);
+        }
+    }
+
+    pub fn add_protected_maker(&mut self, user_account: Pubkey) {
+        self.protected_makers.insert(user_account);
+    }
+
+    pub fn remove_protected_maker(&mut self, user_account: &Pubkey) {
+        self.protected_makers.remove(user_account);
+    }
+
+    pub fn set_max_abs_oracle_offset(&mut self, max_abs_oracle_offset: Option<u32>) {
+        self.max_abs_oracle_offset = max_abs_oracle_offset;
+    }
+
+    /// Sets the tick size orders on a market must be priced in, or with `None` stops
+    /// checking it.
+    pub fn set_tick_size(
+        &mut self,
+        market_index: u16,
+        market_type: MarketType,
+        tick_size: Option<u64>,
+    ) {
+        let market_id = MarketId::new(market_index, market_type);
+        match tick_size {
+            Some(tick_size) => {
+                self.tick_sizes.insert(market_id, tick_size);
+            }
+            None => {
+                self.tick_sizes.remove(&market_id);
+            }
+        }
+    }
+
+    pub fn set_tick_size_policy(&mut self, tick_size_policy: TickSizePolicy) {
+        self.tick_size_policy = tick_size_policy;
+    }
+
+    pub fn set_market_price_band(&mut self, market_price_band: Option<u32>) {
+        self.market_price_band = market_price_band;
+    }
+
+    /// Installs a callback that is told about every fill `update_order` applies. Pass `None`
+    /// to stop recording.
+    pub fn set_fill_recorder(&mut self, fill_recorder: Option<FillRecorder>) {
+        self.fill_recorder = fill_recorder;
+    }
+
+    pub fn set_price_fallback(&mut self, price_fallback: PriceFallback) {
+        self.price_fallback = price_fallback;
+    }
+
+    /// Records the latest oracle price for a market so `PriceFallback::LastKnown` reads can
+    /// fall back to it.
+    pub fn update_oracle_price(
+        &mut self,
+        market_index: u16,
+        market_type: MarketType,
+        oracle_price_data: OraclePriceData,
+    ) {
+        self.last_oracle_prices
+            .insert(MarketId::new(market_index, market_type), oracle_price_data);
+    }
+
+    /// The oracle price a read should use: `oracle_price_data` when the caller has a fresh
+    /// one, otherwise whatever the configured `PriceFallback` allows.
+    pub fn resolve_oracle_price(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        oracle_price_data: Option<&OraclePriceData>,
+    ) -> DriftResult<OraclePriceData> {
+        if let Some(oracle_price_data) = oracle_price_data {
+            return Ok(*oracle_price_data);
+        }
+
+        let last_known = match self.price_fallback {
+            PriceFallback::LastKnown => self
+                .last_oracle_prices
+                .get(&MarketId::new(market_index, market_type)),
+            PriceFallback::RejectQuery => None,
+        };
+
+        last_known.copied().ok_or_else(|| {
+            msg!(
+                "no oracle price for {:?} market {} ({:?})",
+                market_type,
+                market_index,
+                self.price_fallback
+            );
+            ErrorCode::OracleNotFound
+        })
+    }
+
+    pub fn init_from_user_map(&mut self, user_map: &UserMap, slot: u64) -> DriftResult<bool> {
         if self.initialized {
             return Ok(false);
         }
 
+        for user_account in user_map.0.keys() {
+            let user = user_map.get_ref(user_account)?;
+            for order in user.orders.iter() {
+                if order.status == OrderStatus::Init {
+                    continue;
+                }
+
+                self.insert_order(*order, *user_account, slot)?;
+            }
+        }
+
+        self.initialize()?;
         Ok(true)
     }
 
@@ -150,20 +626,315 @@ impl DLOB {
         Ok(true)
     }
 
+    /// `init_from_orders` with the orders parsed from a JSON array of `DLOBOrder`s, e.g. a
+    /// book state saved to disk.
+    #[cfg(feature = "serde")]
+    pub fn init_from_json(&mut self, json: &str, slot: u64) -> DriftResult<bool> {
+        let dlob_orders: DLOBOrders = serde_json::from_str(json).map_err(|e| {
+            msg!("could not parse dlob orders: {}", e);
+            ErrorCode::InvalidOrder
+        })?;
+        self.init_from_orders(dlob_orders, slot)
+    }
+
+    pub fn snapshot_market(&self, market_index: u16, market_type: MarketType) -> MarketSnapshot {
+        let mut orders = DLOBOrders::new();
+        for node_list in self.get_market_node_lists(market_index, market_type) {
+            for node in node_list.iter() {
+                if let (Some(order), Some(user_account)) = (node.order(), node.user_account()) {
+                    orders.push(DLOBOrder {
+                        user: *user_account,
+                        order: *order,
+                    });
+                }
+            }
+        }
+
+        MarketSnapshot {
+            market_index,
+            market_type,
+            orders,
+        }
+    }
+
+    /// Replaces a market's book with `snapshot`, so orders the source has since dropped
+    /// don't survive on the replica.
+    pub fn restore_market(&mut self, snapshot: MarketSnapshot, slot: u64) -> DriftResult<()> {
+        self.clear_market(snapshot.market_index, snapshot.market_type);
+
+        for dlob_order in snapshot.orders {
+            if dlob_order.order.market_index != snapshot.market_index
+                || MarketType::from(dlob_order.order.market_type) != snapshot.market_type
+            {
+                continue;
+            }
+            self.insert_order(dlob_order.order, dlob_order.user, slot)?;
+        }
+
+        Ok(())
+    }
+
+    /// Signatures of every live order in a market, for cheap add/drop detection without
+    /// copying any orders.
+    pub fn order_signatures(&self, market_index: u16, market_type: MarketType) -> Vec<String> {
+        self.get_market_node_lists(market_index, market_type)
+            .into_iter()
+            .flat_map(|node_list| node_list.signatures())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Every open order signature, perp markets first, each market type in sorted order.
+    pub fn open_order_signatures(&self) -> impl Iterator<Item = (MarketType, &str)> {
+        [MarketType::Perp, MarketType::Spot]
+            .into_iter()
+            .flat_map(move |market_type| {
+                self.open_orders
+                    .get(&market_type)
+                    .into_iter()
+                    .flatten()
+                    .map(move |order_signature| (market_type, order_signature.as_str()))
+            })
+    }
+
+    /// Copy of the open order signatures per market type, for diffing against the chain's
+    /// view on reconnect.
+    pub fn open_orders_snapshot(&self) -> HashMap<MarketType, BTreeSet<String>> {
+        self.open_orders.clone()
+    }
+
+    /// Replaces the open order signatures with `open_orders`, e.g. a snapshot taken before a
+    /// reconnect. Follow with `reconcile_open_orders` to drop any the book no longer holds.
+    pub fn restore_open_orders(&mut self, open_orders: HashMap<MarketType, BTreeSet<String>>) {
+        self.open_orders = open_orders;
+        for market_type in [MarketType::Perp, MarketType::Spot] {
+            self.open_orders
+                .entry(market_type)
+                .or_insert_with(BTreeSet::new);
+        }
+    }
+
+    /// Rebuilds `open_orders` from the orders actually linked into the node lists, returning
+    /// how many stale signatures were dropped.
+    pub fn reconcile_open_orders(&mut self) -> usize {
+        let mut open_orders: HashMap<MarketType, BTreeSet<String>> = HashMap::new();
+        open_orders.insert(MarketType::Perp, BTreeSet::new());
+        open_orders.insert(MarketType::Spot, BTreeSet::new());
+
+        for (market_id, _, _, node_list) in self.iter_labeled_lists() {
+            for node in node_list.iter() {
+                if let (Some(order), Some(user_account)) = (node.order(), node.user_account()) {
+                    if order.status == OrderStatus::Open {
+                        open_orders
+                            .entry(market_id.market_type)
+                            .or_default()
+                            .insert(get_order_signature(order.order_id, user_account));
+                    }
+                }
+            }
+        }
+
+        let stale = self
+            .open_orders
+            .iter()
+            .map(|(market_type, order_signatures)| {
+                let live = &open_orders[market_type];
+                order_signatures
+                    .iter()
+                    .filter(|order_signature| !live.contains(*order_signature))
+                    .count()
+            })
+            .sum();
+
+        self.open_orders = open_orders;
+        stale
+    }
+
+    /// Number of open orders `user_account` has across every market.
+    pub fn user_order_count(&self, user_account: &Pubkey) -> usize {
+        let prefix = format!("{}-", user_account);
+        self.open_orders
+            .values()
+            .flatten()
+            .filter(|order_signature| order_signature.starts_with(&prefix))
+            .count()
+    }
+
+    /// Deletes every order `user_account` has in the book, e.g. when its subscription drops,
+    /// and clears its open order signatures for both market types. Returns how many orders
+    /// were removed.
+    pub fn remove_orders_for_user(
+        &mut self,
+        user_account: &Pubkey,
+        slot: u64,
+    ) -> DriftResult<usize> {
+        let orders = self.get_orders_for_user(user_account);
+        for order in &orders {
+            self.delete(*order, *user_account, slot)?;
+        }
+
+        let prefix = format!("{}-", user_account);
+        for open_orders in self.open_orders.values_mut() {
+            open_orders.retain(|order_signature| !order_signature.starts_with(&prefix));
+        }
+
+        Ok(orders.len())
+    }
+
     pub fn handle_order_record(&mut self, record: OrderRecord, slot: u64) -> DriftResult<()> {
         self.insert_order(record.order, record.user, slot)
     }
 
+    /// Applies a chunk of order action records in slot order. The sort is stable, so records
+    /// from the same slot keep their relative order, and aging logic sees monotonic slots
+    /// even if the chunk arrived slightly out of order.
+    pub fn replay_records(
+        &mut self,
+        mut records: Vec<(OrderActionRecord, u64)>,
+    ) -> DriftResult<()> {
+        records.sort_by_key(|(_, slot)| *slot);
+        for (record, slot) in records {
+            self.handle_order_action_record(record, slot)?;
+        }
+        Ok(())
+    }
+
+    /// Applies a fill, cancel, expiry or trigger to the taker and maker orders the record
+    /// names, so the book can be kept in sync from the event stream alone. Placements carry
+    /// no order and are left to `handle_order_record`, and orders the book doesn't hold are
+    /// skipped.
+    pub fn handle_order_action_record(
+        &mut self,
+        record: OrderActionRecord,
+        slot: u64,
+    ) -> DriftResult<()> {
+        let participants = [
+            (
+                record.taker,
+                record.taker_order_id,
+                record.taker_order_cumulative_base_asset_amount_filled,
+            ),
+            (
+                record.maker,
+                record.maker_order_id,
+                record.maker_order_cumulative_base_asset_amount_filled,
+            ),
+        ];
+
+        for (user_account, order_id, cumulative_base_asset_amount_filled) in participants {
+            let (user_account, order_id) = match (user_account, order_id) {
+                (Some(user_account), Some(order_id)) => (user_account, order_id),
+                _ => continue,
+            };
+            let order = match self.get_node(
+                order_id,
+                user_account,
+                record.market_index,
+                record.market_type.into(),
+            ) {
+                Some(node) => match node.order() {
+                    Some(order) => *order,
+                    None => continue,
+                },
+                None => continue,
+            };
+
+            match record.action {
+                OrderAction::Place => {}
+                OrderAction::Fill => {
+                    if let Some(cumulative_base_asset_amount_filled) =
+                        cumulative_base_asset_amount_filled
+                    {
+                        self.update_order(
+                            order,
+                            user_account,
+                            slot,
+                            cumulative_base_asset_amount_filled,
+                        )?;
+                    }
+                }
+                OrderAction::Cancel | OrderAction::Expire => {
+                    self.delete(order, user_account, slot)?;
+                }
+                OrderAction::Trigger => {
+                    let trigger_condition = match order.trigger_condition {
+                        OrderTriggerCondition::Above => OrderTriggerCondition::TriggeredAbove,
+                        OrderTriggerCondition::Below => OrderTriggerCondition::TriggeredBelow,
+                        trigger_condition => trigger_condition,
+                    };
+                    self.trigger(
+                        Order {
+                            trigger_condition,
+                            ..order
+                        },
+                        user_account,
+                        slot,
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Same as `insert_order`, but rejects orders whose fields are inconsistent with their
+    /// type instead of silently filing them into the wrong list.
+    pub fn insert_order_validated(
+        &mut self,
+        order: Order,
+        user_account: Pubkey,
+        slot: u64,
+    ) -> DriftResult<()> {
+        validate_order_fields(&order)?;
+        self.insert_order(order, user_account, slot)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self, order),
+            fields(market_index = order.market_index, order_id = order.order_id)
+        )
+    )]
     pub fn insert_order(
         &mut self,
         order: Order,
         user_account: Pubkey,
         slot: u64,
     ) -> DriftResult<()> {
-        if matches!(order.status, OrderStatus::Init) {
+        if !matches!(order.status, OrderStatus::Open) {
+            self.remove_open_order(&order, &user_account);
+            // a filled or canceled order leaves the book, from whichever list it rested in
+            if let Some(market_node_lists) = self
+                .order_lists
+                .get_mut(&order.market_type.into())
+                .and_then(|market_node_lists_map| {
+                    market_node_lists_map.get_mut(&order.market_index)
+                })
+            {
+                for node_list in market_node_lists.lists_mut() {
+                    node_list.remove(order, user_account)?;
+                }
+            }
             return Ok(());
         }
 
+        if let Some(max_abs_oracle_offset) = self.max_abs_oracle_offset {
+            validate!(
+                order.oracle_price_offset.unsigned_abs() <= max_abs_oracle_offset,
+                ErrorCode::InvalidOrderOracleOffset,
+                "order {} oracle offset {} exceeds max {}",
+                order.order_id,
+                order.oracle_price_offset,
+                max_abs_oracle_offset
+            )?;
+        }
+
+        let order = self.apply_tick_size(order)?;
+
+        let slot = self.clamp_slot(slot);
+
         if !matches!(
             order.order_type,
             OrderType::Market
@@ -177,119 +948,228 @@ impl DLOB {
 
         let market_type = order.market_type;
 
-        if !self.order_lists.contains_key(&market_type.into()) {
+        let has_market_lists = self
+            .order_lists
+            .get(&market_type.into())
+            .map_or(false, |market_node_lists_map| {
+                market_node_lists_map.contains_key(&order.market_index)
+            });
+        if !has_market_lists {
             self.add_order_list(market_type.into(), order.market_index);
         }
 
-        if matches!(order.status, OrderStatus::Open) {
-            let order_signature = format!("{}-{}", user_account, order.order_id);
-            self.open_orders
-                .entry(market_type.into())
+        let order_signature = format!("{}-{}", user_account, order.order_id);
+
+        if order.max_ts != 0 && !order.must_be_triggered() {
+            self.expiry_buckets
+                .entry(order.max_ts)
                 .or_insert_with(HashSet::new)
-                .insert(order_signature);
+                .insert(order_signature.clone());
         }
 
-        if let Some(mut list) = self.get_list_for_order(order, slot) {
+        self.open_orders
+            .entry(market_type.into())
+            .or_insert_with(BTreeSet::new)
+            .insert(order_signature);
+
+        if let Some(list) = self.get_list_for_order_mut(order, slot) {
             list.insert(order, user_account)?;
         }
 
+        dlob_trace!(
+            market_type = ?MarketType::from(order.market_type),
+            node_type = ?determine_node_type(&order, slot),
+            "inserted order"
+        );
+
         Ok(())
     }
 
-    fn add_order_list(&mut self, market_type: MarketType, market_index: u16) {
-        let resting_limit = MarketNodeLists::RestingLimit(SideNodeList {
-            ask: NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Asc),
-            bid: NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc),
-        });
-        let floating_limit = MarketNodeLists::FloatingLimit(SideNodeList {
-            ask: NodeList::new(DLOBNodeType::FloatingLimit, SortDirection::Asc),
-            bid: NodeList::new(DLOBNodeType::FloatingLimit, SortDirection::Desc),
-        });
-        let taking_limit = MarketNodeLists::TakingLimit(SideNodeList {
-            ask: NodeList::new(DLOBNodeType::TakingLimit, SortDirection::Asc),
-            bid: NodeList::new(DLOBNodeType::TakingLimit, SortDirection::Asc),
-        });
-        let market = MarketNodeLists::Market(SideNodeList {
-            ask: NodeList::new(DLOBNodeType::Market, SortDirection::Asc),
-            bid: NodeList::new(DLOBNodeType::Market, SortDirection::Asc),
-        });
-        let trigger = MarketNodeLists::Trigger(TriggerNodeList {
-            above: NodeList::new(DLOBNodeType::Trigger, SortDirection::Asc),
-            below: NodeList::new(DLOBNodeType::Trigger, SortDirection::Desc),
-        });
+    fn remove_open_order(&mut self, order: &Order, user_account: &Pubkey) {
+        if let Some(open_orders) = self.open_orders.get_mut(&order.market_type.into()) {
+            open_orders.remove(&get_order_signature(order.order_id, user_account));
+        }
+    }
 
-        let market_node_lists = vec![resting_limit, floating_limit, taking_limit, market, trigger];
+    /// Checks `order`'s limit price against its market's tick size, rounding it or failing
+    /// per `tick_size_policy`. Orders without a limit price pass through.
+    fn apply_tick_size(&self, order: Order) -> DriftResult<Order> {
+        let market_id = MarketId::new(order.market_index, order.market_type.into());
+        let tick_size = match self.tick_sizes.get(&market_id) {
+            Some(tick_size) if *tick_size > 0 => *tick_size,
+            _ => return Ok(order),
+        };
+
+        if order.price % tick_size == 0 {
+            return Ok(order);
+        }
 
-        if let Some(market_node_lists_map) = self.order_lists.get_mut(&market_type) {
-            for market_node_list in market_node_lists {
-                market_node_lists_map.insert(market_index, market_node_list);
+        match self.tick_size_policy {
+            TickSizePolicy::Reject => {
+                msg!(
+                    "order {} price {} is not a multiple of tick size {}",
+                    order.order_id,
+                    order.price,
+                    tick_size
+                );
+                Err(ErrorCode::InvalidOrder)
             }
-        } else {
-            let mut new_market_node_lists_map = HashMap::new();
-            for market_node_list in market_node_lists {
-                new_market_node_lists_map.insert(market_index, market_node_list);
-            }
-            self.order_lists
-                .insert(market_type, new_market_node_lists_map);
+            TickSizePolicy::Round => Ok(Order {
+                price: standardize_price(order.price, tick_size, order.direction)?,
+                ..order
+            }),
         }
     }
 
-    fn get_list_for_order(&self, order: Order, slot: u64) -> Option<NodeList> {
+    /// Reports which list `order` would land in and how many orders would be ahead of it,
+    /// without touching the book. `None` if the book has no list for it yet.
+    pub fn preview_insert(
+        &self,
+        order: Order,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> DriftResult<Option<InsertPreview>> {
+        let node_list = match self.get_list_for_order(order, slot) {
+            Some(node_list) => node_list,
+            None => return Ok(None),
+        };
         let node_type = determine_node_type(&order, slot);
-        let is_inactive_trigger_order = node_type == DLOBNodeType::Trigger;
-        let order_sub_type = determine_sub_type(&order, is_inactive_trigger_order);
-
-        self.order_lists
-            .get(&order.market_type.into())
-            .and_then(|d| d.get(&order.market_index))
-            .and_then(|market_node_lists| match market_node_lists {
-                MarketNodeLists::RestingLimit(list)
-                | MarketNodeLists::FloatingLimit(list)
-                | MarketNodeLists::TakingLimit(list)
-                | MarketNodeLists::Market(list) => {
-                    if let OrderSubType::Side(side) = order_sub_type {
-                        match side {
-                            Side::Ask => Some(&list.ask),
-                            Side::Bid => Some(&list.bid),
-                        }
-                    } else {
-                        None
-                    }
-                }
-                MarketNodeLists::Trigger(list) => {
-                    if let OrderSubType::Trigger(trigger) = order_sub_type {
-                        match trigger {
-                            OrderTriggerCondition::Above => Some(&list.above),
-                            OrderTriggerCondition::Below => Some(&list.below),
-                            _ => None,
-                        }
-                    } else {
-                        None
-                    }
-                }
-            })
-            .cloned()
+        let price = self
+            .node_factory
+            .create_node(node_type.clone(), order, Pubkey::default())
+            .get_price(oracle_price_data, slot);
+
+        Ok(Some(InsertPreview {
+            node_type,
+            side: Side::from_direction(order.direction),
+            rank: node_list.insert_position(order, Pubkey::default())?,
+            price,
+        }))
     }
 
-    fn delete(&mut self, order: Order, user_account: Pubkey, slot: u64) -> DriftResult<()> {
-        if order.status == OrderStatus::Init {
-            return Ok(());
+    /// Removes every order whose `max_ts` is before `now_ts`, returning the expired orders.
+    /// Only the expiry buckets that have passed are visited.
+    pub fn expire_orders(&mut self, now_ts: i64) -> DriftResult<DLOBOrders> {
+        let live_buckets = self.expiry_buckets.split_off(&now_ts);
+        let expired_buckets = std::mem::replace(&mut self.expiry_buckets, live_buckets);
+
+        let slot = self.latest_slot;
+        let mut expired_orders = DLOBOrders::new();
+        for order_signature in expired_buckets.into_values().flatten() {
+            let node = match self.find_node(&order_signature) {
+                Some(node) => node,
+                None => continue,
+            };
+
+            if let (Some(order), Some(user_account)) = (node.order(), node.user_account()) {
+                self.delete(*order, *user_account, slot)?;
+                expired_orders.push(DLOBOrder {
+                    user: *user_account,
+                    order: *order,
+                });
+            }
         }
 
-        self.update_resting_limit_orders(slot)?;
+        Ok(expired_orders)
+    }
 
-        if let Some(mut list) = self.get_list_for_order(order, slot) {
-            list.remove(order, user_account)?
-        }
+    fn add_order_list(&mut self, market_type: MarketType, market_index: u16) {
+        let node_factory = self.node_factory.clone();
+        let new_list = |node_type: DLOBNodeType, side| {
+            NodeList::with_factory(
+                node_type.clone(),
+                sort_direction_for(node_type, side),
+                node_factory.clone(),
+            )
+        };
+        let side_lists = |node_type: DLOBNodeType| SideNodeList {
+            ask: new_list(node_type.clone(), Side::Ask),
+            bid: new_list(node_type, Side::Bid),
+        };
+
+        let market_node_lists = MarketNodeLists {
+            resting_limit: side_lists(DLOBNodeType::RestingLimit),
+            floating_limit: side_lists(DLOBNodeType::FloatingLimit),
+            taking_limit: side_lists(DLOBNodeType::TakingLimit),
+            market: side_lists(DLOBNodeType::Market),
+            // orders triggering above the oracle fire lowest trigger first, like asks
+            trigger: TriggerNodeList {
+                above: new_list(DLOBNodeType::Trigger, Side::Ask),
+                below: new_list(DLOBNodeType::Trigger, Side::Bid),
+            },
+        };
+
+        self.order_lists
+            .entry(market_type)
+            .or_insert_with(HashMap::new)
+            .insert(market_index, market_node_lists);
+    }
+
+    fn get_list_for_order(&self, order: Order, slot: u64) -> Option<&NodeList> {
+        let (market_type, market_index, node_type, order_sub_type) = list_index(&order, slot);
+        self.order_lists
+            .get(&market_type)
+            .and_then(|market_node_lists_map| market_node_lists_map.get(&market_index))
+            .and_then(|market_node_lists| market_node_lists.list(node_type, order_sub_type))
+    }
+
+    /// Borrows the stored list `order` belongs in, so mutations land in the book itself.
+    fn get_list_for_order_mut(&mut self, order: Order, slot: u64) -> Option<&mut NodeList> {
+        let (market_type, market_index, node_type, order_sub_type) = list_index(&order, slot);
+        self.order_lists
+            .get_mut(&market_type)
+            .and_then(|market_node_lists_map| market_node_lists_map.get_mut(&market_index))
+            .and_then(|market_node_lists| market_node_lists.list_mut(node_type, order_sub_type))
+    }
+
+    /// Removes `order` from the book, e.g. on a cancel or expiry.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self, order),
+            fields(market_index = order.market_index, order_id = order.order_id)
+        )
+    )]
+    pub fn delete(&mut self, order: Order, user_account: Pubkey, slot: u64) -> DriftResult<()> {
+        if order.status == OrderStatus::Init {
+            return Ok(());
+        }
+
+        self.remove_open_order(&order, &user_account);
+
+        let slot = self.clamp_slot(slot);
+        self.update_resting_limit_orders(slot)?;
+
+        if let Some(list) = self.get_list_for_order_mut(order, slot) {
+            list.remove(order, user_account)?
+        }
+
+        dlob_trace!(
+            market_type = ?MarketType::from(order.market_type),
+            node_type = ?determine_node_type(&order, slot),
+            "deleted order"
+        );
 
         Ok(())
     }
 
-    fn trigger(&mut self, order: Order, user_account: Pubkey, slot: u64) -> DriftResult<()> {
+    /// Moves a triggered order out of the trigger lists into the list for its type. `order`
+    /// must carry its triggered condition; untriggered orders are ignored.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self, order),
+            fields(market_index = order.market_index, order_id = order.order_id)
+        )
+    )]
+    pub fn trigger(&mut self, order: Order, user_account: Pubkey, slot: u64) -> DriftResult<()> {
         if order.status == OrderStatus::Init {
             return Ok(());
         }
 
+        let slot = self.clamp_slot(slot);
         self.update_resting_limit_orders(slot)?;
 
         if order.trigger_condition == OrderTriggerCondition::Above
@@ -298,45 +1178,72 @@ impl DLOB {
             return Ok(());
         }
 
-        if let Some(market_node_lists) = self.order_lists.get_mut(&order.market_type.into()) {
-            if let Some(node_list) = market_node_lists.get_mut(&order.market_index) {
-                let trigger_list = match node_list {
-                    MarketNodeLists::Trigger(trigger_node_list) => {
-                        Some(if order.trigger_condition == OrderTriggerCondition::Above {
-                            &mut trigger_node_list.above
-                        } else {
-                            &mut trigger_node_list.below
-                        })
-                    }
-                    _ => None,
-                };
-
-                if let Some(trigger_list) = trigger_list {
-                    trigger_list.remove(order, user_account)?;
-                }
-
-                if let Some(mut node_list) = self.get_list_for_order(order, slot) {
-                    node_list.insert(order, user_account)?;
-                }
+        if let Some(market_node_lists) = self
+            .order_lists
+            .get_mut(&order.market_type.into())
+            .and_then(|market_node_lists_map| market_node_lists_map.get_mut(&order.market_index))
+        {
+            let trigger_list = if order.trigger_condition == OrderTriggerCondition::TriggeredAbove {
+                &mut market_node_lists.trigger.above
+            } else {
+                &mut market_node_lists.trigger.below
+            };
+            trigger_list.remove(order, user_account)?;
+
+            if let Some(node_list) = self.get_list_for_order_mut(order, slot) {
+                node_list.insert(order, user_account)?;
             }
+
+            dlob_trace!(
+                market_type = ?MarketType::from(order.market_type),
+                node_type = ?determine_node_type(&order, slot),
+                "triggered order"
+            );
         }
 
         Ok(())
     }
 
-    fn update_order(
+    /// Applies a fill that took `order` to `cumulative_base_asset_amount_filled`, removing it
+    /// once fully filled. The stored order keeps its size and tracks the fill in
+    /// `base_asset_amount_filled`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip(self, order),
+            fields(market_index = order.market_index, order_id = order.order_id)
+        )
+    )]
+    pub fn update_order(
         &mut self,
         order: Order,
         user_account: Pubkey,
         slot: u64,
         cumulative_base_asset_amount_filled: u64,
     ) -> DriftResult<()> {
+        let slot = self.clamp_slot(slot);
         self.update_resting_limit_orders(slot)?;
 
-        if order
-            .base_asset_amount
-            .eq(&cumulative_base_asset_amount_filled)
-        {
+        let fill_base =
+            cumulative_base_asset_amount_filled.saturating_sub(order.base_asset_amount_filled);
+        if fill_base > 0 {
+            self.record_recent_fill(
+                MarketId::new(order.market_index, order.market_type.into()),
+                fill_base,
+                slot,
+            );
+            if let Some(fill_recorder) = self.fill_recorder.as_mut() {
+                fill_recorder(
+                    &get_order_signature(order.order_id, &user_account),
+                    fill_base,
+                    order.price as i128,
+                    slot,
+                );
+            }
+        }
+
+        if cumulative_base_asset_amount_filled >= order.base_asset_amount {
             self.delete(order, user_account, slot)?;
             return Ok(());
         }
@@ -348,23 +1255,140 @@ impl DLOB {
             return Ok(());
         }
 
-        let mut new_order = order;
+        let new_order = Order {
+            base_asset_amount_filled: cumulative_base_asset_amount_filled,
+            ..order
+        };
 
-        new_order.base_asset_amount = cumulative_base_asset_amount_filled;
-
-        if let Some(mut node_list) = self.get_list_for_order(order, slot) {
+        if let Some(node_list) = self.get_list_for_order_mut(order, slot) {
             node_list.update(new_order, user_account)?;
         }
 
+        dlob_trace!(
+            market_type = ?MarketType::from(order.market_type),
+            node_type = ?determine_node_type(&order, slot),
+            "updated order"
+        );
+
         Ok(())
     }
 
+    fn record_recent_fill(&mut self, market_id: MarketId, fill_base: u64, slot: u64) {
+        let fills = self
+            .recent_fills
+            .entry(market_id)
+            .or_insert_with(VecDeque::new);
+        fills.push_back((slot, fill_base));
+
+        let window_start = slot.saturating_sub(FILL_RATE_WINDOW_SLOTS);
+        while fills
+            .front()
+            .map_or(false, |(fill_slot, _)| *fill_slot <= window_start)
+        {
+            fills.pop_front();
+        }
+    }
+
+    /// Base filled on the market within the last `FILL_RATE_WINDOW_SLOTS`.
+    fn recent_fill_base(&self, market_id: &MarketId) -> u64 {
+        let window_start = self.latest_slot.saturating_sub(FILL_RATE_WINDOW_SLOTS);
+        self.recent_fills.get(market_id).map_or(0, |fills| {
+            fills
+                .iter()
+                .filter(|(fill_slot, _)| *fill_slot > window_start)
+                .fold(0_u64, |total, (_, fill_base)| {
+                    total.saturating_add(*fill_base)
+                })
+        })
+    }
+
+    /// Rough time until the maker order `order_signature` fills: the base queued ahead of
+    /// it plus its own remaining base, drained at the market's fill rate over the last
+    /// `FILL_RATE_WINDOW_SLOTS`. Floating orders are ranked off `oracle_price_data`. `None`
+    /// if the order isn't resting or the market has no recent fills.
+    pub fn estimate_time_to_fill(
+        &self,
+        order_signature: &str,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<Duration> {
+        let node = self.find_node(order_signature)?;
+        let order = node.order()?;
+        let user_account = node.user_account()?;
+        let market_id = MarketId::new(order.market_index, order.market_type.into());
+
+        let recent_fill_base = self.recent_fill_base(&market_id);
+        if recent_fill_base == 0 {
+            return None;
+        }
+
+        let (_, base_ahead) = self.queue_position(
+            order.order_id,
+            *user_account,
+            market_id.market_index,
+            market_id.market_type,
+            self.latest_slot,
+            oracle_price_data,
+        )?;
+
+        let base_to_fill = base_ahead.saturating_add(get_remaining_base(&node)) as u128;
+        let slots = base_to_fill * FILL_RATE_WINDOW_SLOTS as u128 / recent_fill_base as u128;
+        let slots = u64::try_from(slots).unwrap_or(u64::MAX);
+
+        Some(Duration::from_millis(
+            slots.saturating_mul(SLOT_DURATION_MS),
+        ))
+    }
+
+    /// Shrinks an open order to `new_base_asset_amount`, e.g. after a partial cancel, so
+    /// depth reads see the reduced size straight away. Reducing to or below the filled
+    /// amount removes the order.
+    pub fn reduce_order(
+        &mut self,
+        order: Order,
+        user_account: Pubkey,
+        new_base_asset_amount: u64,
+        slot: u64,
+    ) -> DriftResult<()> {
+        let slot = self.clamp_slot(slot);
+        self.update_resting_limit_orders(slot)?;
+
+        validate!(
+            new_base_asset_amount <= order.base_asset_amount,
+            ErrorCode::InvalidOrder,
+            "cannot reduce order {} from {} up to {}",
+            order.order_id,
+            order.base_asset_amount,
+            new_base_asset_amount
+        )?;
+
+        if new_base_asset_amount <= order.base_asset_amount_filled {
+            return self.delete(order, user_account, slot);
+        }
+
+        let reduced_order = Order {
+            base_asset_amount: new_base_asset_amount,
+            ..order
+        };
+
+        if let Some(node_list) = self.get_list_for_order_mut(order, slot) {
+            node_list.update(reduced_order, user_account)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the slot a mutation should be applied at, never going backwards.
+    fn clamp_slot(&mut self, slot: u64) -> u64 {
+        self.latest_slot = self.latest_slot.max(slot);
+        self.latest_slot
+    }
+
     fn update_resting_limit_orders(&mut self, slot: u64) -> DriftResult<()> {
-        if slot <= self.max_slot_for_resting_limit_orders as u64 {
+        if slot <= self.max_slot_for_resting_limit_orders {
             return Ok(());
         }
 
-        self.max_slot_for_resting_limit_orders = 0;
+        self.max_slot_for_resting_limit_orders = slot;
 
         self.update_resting_limit_orders_for_market_type(slot, MarketType::Perp)?;
         self.update_resting_limit_orders_for_market_type(slot, MarketType::Spot)?;
@@ -381,46 +1405,36 @@ impl DLOB {
             for market_node_lists in map.values_mut() {
                 let mut nodes_to_update = Vec::new();
 
-                if let MarketNodeLists::TakingLimit(taking_limit) = market_node_lists {
-                    for node in taking_limit.ask.iter() {
+                let taking_limit = &market_node_lists.taking_limit;
+                for (side, node_list) in [
+                    (Side::Ask, &taking_limit.ask),
+                    (Side::Bid, &taking_limit.bid),
+                ] {
+                    for node in node_list.iter() {
                         if let Some(order) = node.order() {
-                            if !order.is_resting_limit_order(slot).unwrap() {
+                            if !order.is_resting_limit_order(slot).unwrap_or(false) {
                                 continue;
                             }
                         }
-                        nodes_to_update.push((Side::Ask, node));
-                    }
-
-                    for node in taking_limit.bid.iter() {
-                        if let Some(order) = node.order() {
-                            if !order.is_resting_limit_order(slot).unwrap() {
-                                continue;
-                            }
-                        }
-                        nodes_to_update.push((Side::Bid, node));
+                        nodes_to_update.push((side, node));
                     }
                 }
 
                 for (side, node) in nodes_to_update {
-                    if let MarketNodeLists::RestingLimit(resting_limit) = market_node_lists {
-                        match side {
-                            Side::Ask => {
-                                if let Some(order) = node.order() {
-                                    if let Some(user_account) = node.user_account() {
-                                        resting_limit.ask.remove(*order, *user_account)?;
-                                        resting_limit.ask.insert(*order, *user_account)?;
-                                    }
-                                }
-                            }
-                            Side::Bid => {
-                                if let Some(order) = node.order() {
-                                    if let Some(user_account) = node.user_account() {
-                                        resting_limit.bid.remove(*order, *user_account)?;
-                                        resting_limit.bid.insert(*order, *user_account)?;
-                                    }
-                                }
-                            }
-                        }
+                    let (taking_list, resting_list) = match side {
+                        Side::Ask => (
+                            &mut market_node_lists.taking_limit.ask,
+                            &mut market_node_lists.resting_limit.ask,
+                        ),
+                        Side::Bid => (
+                            &mut market_node_lists.taking_limit.bid,
+                            &mut market_node_lists.resting_limit.bid,
+                        ),
+                    };
+                    if let (Some(order), Some(user_account)) = (node.order(), node.user_account()) {
+                        taking_list.remove(*order, *user_account)?;
+                        resting_list.remove(*order, *user_account)?;
+                        resting_list.insert(*order, *user_account)?;
                     }
                 }
             }
@@ -428,16 +1442,1562 @@ impl DLOB {
         Ok(())
     }
 
-    fn get_order(&self, order_id: u32, user_account: Pubkey) -> DriftResult<Option<Order>> {
-        let order_sig = get_order_signature(order_id, &user_account);
-        for node_list in self.get_node_lists() {
-            if let Some(node) = node_list.get(&order_sig) {
-                if let Some(order) = node.order() {
-                    return Ok(Some(*order));
+    /// Looks an order up by id, e.g. after learning it from an event. Scans every list of
+    /// every market, stopping at the first match; use `get_node` when the market is known.
+    pub fn get_order(&self, order_id: u32, user_account: Pubkey) -> DriftResult<Option<Order>> {
+        let order_signature = get_order_signature(order_id, &user_account);
+        Ok(self
+            .find_node(&order_signature)
+            .and_then(|node| node.order().copied()))
+    }
+
+    /// Base still unfilled on an order after every fill applied through `update_order`.
+    /// `None` if the order isn't in the book, e.g. because it has fully filled.
+    pub fn order_remaining(
+        &self,
+        order_id: u32,
+        user_account: Pubkey,
+        market_index: u16,
+        market_type: MarketType,
+    ) -> Option<u64> {
+        self.get_node(order_id, user_account, market_index, market_type)
+            .map(|node| get_remaining_base(&node))
+    }
+
+    /// Shared handle to the node for an order on the given market, without copying the
+    /// order out. Updates replace the node, so re-fetch to observe them.
+    pub fn get_node(
+        &self,
+        order_id: u32,
+        user_account: Pubkey,
+        market_index: u16,
+        market_type: MarketType,
+    ) -> Option<Arc<dyn DLOBNode>> {
+        let order_signature = get_order_signature(order_id, &user_account);
+        self.get_market_node_lists(market_index, market_type)
+            .into_iter()
+            .find_map(|node_list| node_list.get(&order_signature).cloned())
+    }
+
+    fn get_market_ids(&self) -> Vec<MarketId> {
+        self.order_lists
+            .iter()
+            .flat_map(|(market_type, market_node_lists_map)| {
+                market_node_lists_map
+                    .keys()
+                    .map(move |market_index| MarketId::new(*market_index, *market_type))
+            })
+            .collect()
+    }
+
+    fn find_node(&self, order_signature: &str) -> Option<Arc<dyn DLOBNode>> {
+        self.iter_labeled_lists()
+            .find_map(|(_, _, _, node_list)| node_list.get(order_signature).cloned())
+    }
+
+    fn get_market_node_lists(&self, market_index: u16, market_type: MarketType) -> Vec<&NodeList> {
+        self.order_lists
+            .get(&market_type)
+            .and_then(|market_node_lists_map| market_node_lists_map.get(&market_index))
+            .map_or_else(Vec::new, |market_node_lists| {
+                market_node_lists
+                    .labeled_lists()
+                    .into_iter()
+                    .map(|(_, _, node_list)| node_list)
+                    .collect()
+            })
+    }
+
+    /// Best resting bid price. Taking limit orders that have aged into resting orders by
+    /// `slot` are included even if the lists haven't been refreshed to `slot` yet.
+    pub fn get_best_bid(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<i128> {
+        self.get_best_price(
+            market_index,
+            market_type,
+            Side::Bid,
+            slot,
+            oracle_price_data,
+        )
+    }
+
+    /// Best resting ask price, see `get_best_bid`.
+    pub fn get_best_ask(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<i128> {
+        self.get_best_price(
+            market_index,
+            market_type,
+            Side::Ask,
+            slot,
+            oracle_price_data,
+        )
+    }
+
+    fn get_best_price(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        side: Side,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<i128> {
+        let best_maker_price =
+            self.get_best_maker_price(market_index, market_type, side, slot, oracle_price_data);
+
+        let vamm_price = self
+            .get_vamm_node(market_index, market_type, side)
+            .map(|vamm_node| vamm_node.get_price(oracle_price_data, slot));
+
+        let matured_taking_prices = self
+            .get_side_list(market_index, market_type, DLOBNodeType::TakingLimit, side)
+            .into_iter()
+            .flat_map(|node_list| node_list.iter_priced(oracle_price_data, slot))
+            .filter(|(_, node)| {
+                !node.is_base_filled()
+                    && node.order().map_or(false, |order| {
+                        order.is_resting_limit_order(slot).unwrap_or(false)
+                    })
+            })
+            .map(|(price, _)| price);
+
+        let prices = best_maker_price
+            .into_iter()
+            .chain(vamm_price)
+            .chain(matured_taking_prices);
+        match side {
+            Side::Bid => prices.max(),
+            Side::Ask => prices.min(),
+        }
+    }
+
+    /// Best bid and ask if the oracle were at each of `oracle_prices`, for scenario analysis.
+    /// Floating orders and the vAMM are repriced, and trigger limit orders that the
+    /// hypothetical price would trigger join the book at their limit price.
+    pub fn top_of_book_scenarios(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        oracle_prices: &[i128],
+        slot: u64,
+    ) -> Vec<TopOfBook> {
+        let trigger_limit_orders: Vec<Order> = self
+            .get_market_node_lists(market_index, market_type)
+            .into_iter()
+            .flat_map(|node_list| node_list.iter())
+            .filter_map(|node| node.order().copied())
+            .filter(|order| {
+                order.order_type == OrderType::TriggerLimit
+                    && order.must_be_triggered()
+                    && !order.triggered()
+            })
+            .collect();
+
+        oracle_prices
+            .iter()
+            .map(|&oracle_price| {
+                let oracle_price_data = OraclePriceData {
+                    price: oracle_price as i64,
+                    ..OraclePriceData::default()
+                };
+                let best_price = |side| {
+                    let triggered_prices = trigger_limit_orders
+                        .iter()
+                        .filter(|order| {
+                            Side::from_direction(order.direction) == side
+                                && order_satisfies_trigger_condition(
+                                    order,
+                                    oracle_price.max(0) as u64,
+                                )
+                                .unwrap_or(false)
+                        })
+                        .map(|order| order.price as i128);
+                    let prices = self
+                        .get_best_price(market_index, market_type, side, slot, &oracle_price_data)
+                        .into_iter()
+                        .chain(triggered_prices);
+                    match side {
+                        Side::Bid => prices.max(),
+                        Side::Ask => prices.min(),
+                    }
+                };
+
+                TopOfBook {
+                    oracle_price,
+                    best_bid: best_price(Side::Bid),
+                    best_ask: best_price(Side::Ask),
                 }
+            })
+            .collect()
+    }
+
+    /// Registers (or with `None`, removes) the AMM of a perp market so its vAMM quotes count
+    /// towards the best bid and ask and can fill takers.
+    pub fn set_vamm(&mut self, market_index: u16, amm: Option<AMM>) {
+        match amm {
+            Some(amm) => {
+                self.vamms.insert(market_index, amm);
+            }
+            None => {
+                self.vamms.remove(&market_index);
+            }
+        }
+    }
+
+    /// The vAMM quote for one side of a market, if its AMM has been registered.
+    pub fn get_vamm_node(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        side: Side,
+    ) -> Option<Arc<dyn DLOBNode>> {
+        if market_type != MarketType::Perp {
+            return None;
+        }
+        self.vamms
+            .get(&market_index)
+            .map(|amm| Arc::new(VAMMNode::new(side, *amm)) as Arc<dyn DLOBNode>)
+    }
+
+    /// Base that could cross right now between the book and `vamm`: resting and floating bids
+    /// at or above the vAMM's ask plus asks at or below its bid, all of which the vAMM would
+    /// absorb. `vamm`'s own side is ignored, both of its quotes are used.
+    pub fn crossable_size(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+        vamm: &VAMMNode,
+    ) -> u64 {
+        [Side::Bid, Side::Ask]
+            .into_iter()
+            .map(|book_side| {
+                let vamm_side = match book_side {
+                    Side::Bid => Side::Ask,
+                    Side::Ask => Side::Bid,
+                };
+                let vamm_price =
+                    VAMMNode::new(vamm_side, vamm.amm).get_price(oracle_price_data, slot);
+
+                self.get_maker_nodes(
+                    market_index,
+                    market_type,
+                    book_side,
+                    slot,
+                    oracle_price_data,
+                )
+                .into_iter()
+                .take_while(|(price, _)| is_crossing(book_side, Some(*price), vamm_price))
+                .fold(0_u64, |size, (_, node)| {
+                    size.saturating_add(get_remaining_base(&node))
+                })
+            })
+            .fold(0_u64, u64::saturating_add)
+    }
+
+    /// Best resting or floating maker price. Each list's head is its best order, so unless
+    /// both lists hold orders this reads a single head without allocating or merging.
+    fn get_best_maker_price(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        side: Side,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<i128> {
+        let resting =
+            self.get_side_list(market_index, market_type, DLOBNodeType::RestingLimit, side);
+        let floating =
+            self.get_side_list(market_index, market_type, DLOBNodeType::FloatingLimit, side);
+        let is_empty = |node_list: Option<&NodeList>| node_list.map_or(true, NodeList::is_empty);
+
+        let fast_path_list = match (is_empty(resting), is_empty(floating)) {
+            (true, true) => return None,
+            (false, true) => resting,
+            (true, false) => floating,
+            (false, false) => {
+                return self
+                    .get_maker_nodes(market_index, market_type, side, slot, oracle_price_data)
+                    .into_iter()
+                    .next()
+                    .map(|(price, _)| price);
+            }
+        };
+
+        fast_path_list
+            .into_iter()
+            .flat_map(|node_list| node_list.iter())
+            .find(|node| !node.is_base_filled())
+            .map(|node| node.get_price(oracle_price_data, slot))
+    }
+
+    pub fn get_worst_bid(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<i128> {
+        self.get_worst_price(
+            market_index,
+            market_type,
+            Side::Bid,
+            slot,
+            oracle_price_data,
+        )
+    }
+
+    pub fn get_worst_ask(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<i128> {
+        self.get_worst_price(
+            market_index,
+            market_type,
+            Side::Ask,
+            slot,
+            oracle_price_data,
+        )
+    }
+
+    fn get_worst_price(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        side: Side,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<i128> {
+        let prices = [DLOBNodeType::RestingLimit, DLOBNodeType::FloatingLimit]
+            .into_iter()
+            .filter_map(|node_type| self.get_side_list(market_index, market_type, node_type, side))
+            .filter_map(|node_list| node_list.tail())
+            .map(|node| node.get_price(oracle_price_data, slot));
+
+        match side {
+            Side::Bid => prices.min(),
+            Side::Ask => prices.max(),
+        }
+    }
+
+    /// Base that would trade if every crossing resting bid were matched against every
+    /// crossing resting ask, best prices first.
+    pub fn crossed_base_volume(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> u64 {
+        let bids = self.get_maker_nodes(
+            market_index,
+            market_type,
+            Side::Bid,
+            slot,
+            oracle_price_data,
+        );
+        let asks = self.get_maker_nodes(
+            market_index,
+            market_type,
+            Side::Ask,
+            slot,
+            oracle_price_data,
+        );
+
+        let mut bids = bids
+            .into_iter()
+            .map(|(price, node)| (price, get_remaining_base(&node)));
+        let mut asks = asks
+            .into_iter()
+            .map(|(price, node)| (price, get_remaining_base(&node)));
+
+        let mut crossed_base = 0_u64;
+        let mut bid = bids.next();
+        let mut ask = asks.next();
+
+        while let (Some((bid_price, bid_base)), Some((ask_price, ask_base))) = (bid, ask) {
+            if bid_price < ask_price {
+                break;
             }
+
+            let matched = bid_base.min(ask_base);
+            crossed_base = crossed_base.saturating_add(matched);
+
+            bid = if bid_base > matched {
+                Some((bid_price, bid_base - matched))
+            } else {
+                bids.next()
+            };
+            ask = if ask_base > matched {
+                Some((ask_price, ask_base - matched))
+            } else {
+                asks.next()
+            };
         }
-        Ok(None)
+
+        crossed_base
+    }
+
+    pub fn get_mid(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        source: MidSource,
+        oracle_price_data: &OraclePriceData,
+        slot: u64,
+    ) -> Option<i128> {
+        let best_maker = |side: Side| {
+            self.get_maker_nodes(market_index, market_type, side, slot, oracle_price_data)
+                .into_iter()
+                .next()
+        };
+
+        match source {
+            MidSource::Oracle => Some(oracle_price_data.price as i128),
+            MidSource::Book => {
+                let (bid_price, _) = best_maker(Side::Bid)?;
+                let (ask_price, _) = best_maker(Side::Ask)?;
+                Some((bid_price + ask_price) / 2)
+            }
+            MidSource::Weighted => {
+                let (bid_price, bid_node) = best_maker(Side::Bid)?;
+                let (ask_price, ask_node) = best_maker(Side::Ask)?;
+                let bid_size = get_remaining_base(&bid_node) as i128;
+                let ask_size = get_remaining_base(&ask_node) as i128;
+                let total_size = bid_size + ask_size;
+                if total_size == 0 {
+                    return Some((bid_price + ask_price) / 2);
+                }
+                Some((bid_price * ask_size + ask_price * bid_size) / total_size)
+            }
+        }
+    }
+
+    /// Average of the size-weighted bid and ask prices over the top `depth` levels of each
+    /// side. `None` unless both sides have liquidity.
+    pub fn depth_weighted_mid(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        depth: usize,
+        oracle_price_data: &OraclePriceData,
+        slot: u64,
+    ) -> Option<i128> {
+        let l2 = self.get_l2(
+            market_index,
+            market_type,
+            slot,
+            oracle_price_data,
+            L2Options {
+                depth,
+                ..L2Options::default()
+            },
+        );
+
+        let weighted_price = |levels: &[L2Level]| {
+            let (notional, size) =
+                levels
+                    .iter()
+                    .fold((0_i128, 0_i128), |(notional, size), level| {
+                        (
+                            notional + level.price * level.size as i128,
+                            size + level.size as i128,
+                        )
+                    });
+            (size > 0).then(|| notional / size)
+        };
+
+        let bid = weighted_price(&l2.bids)?;
+        let ask = weighted_price(&l2.asks)?;
+
+        Some((bid + ask) / 2)
+    }
+
+    /// Quote lost, in QUOTE_PRECISION, by buying `base_asset_amount` from the asks and
+    /// selling it straight back into the bids, i.e. the average buy price minus the average
+    /// sell price times the size. `None` if either side can't fill the whole size.
+    pub fn round_trip_cost(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        base_asset_amount: u64,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<i128> {
+        let buy_notional = self.sweep_notional(
+            market_index,
+            market_type,
+            Side::Ask,
+            base_asset_amount,
+            slot,
+            oracle_price_data,
+        )?;
+        let sell_notional = self.sweep_notional(
+            market_index,
+            market_type,
+            Side::Bid,
+            base_asset_amount,
+            slot,
+            oracle_price_data,
+        )?;
+
+        Some((buy_notional - sell_notional) / BASE_PRECISION_I128)
+    }
+
+    /// Price times base summed over the best makers on `maker_side` until
+    /// `base_asset_amount` is filled, or `None` if the side runs out first.
+    fn sweep_notional(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        maker_side: Side,
+        base_asset_amount: u64,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<i128> {
+        let mut base_remaining = base_asset_amount;
+        let mut notional = 0_i128;
+
+        for (price, node) in self.get_maker_nodes(
+            market_index,
+            market_type,
+            maker_side,
+            slot,
+            oracle_price_data,
+        ) {
+            if base_remaining == 0 {
+                break;
+            }
+            let fill_base = base_remaining.min(get_remaining_base(&node));
+            notional = notional.saturating_add(price.saturating_mul(fill_base as i128));
+            base_remaining -= fill_base;
+        }
+
+        if base_remaining == 0 {
+            Some(notional)
+        } else {
+            None
+        }
+    }
+
+    /// Resting and floating limit liquidity aggregated into price levels, shaped by
+    /// `options`.
+    pub fn get_l2(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+        options: L2Options,
+    ) -> L2OrderBook {
+        self.get_l2_with_price_source(
+            market_index,
+            market_type,
+            slot,
+            oracle_price_data,
+            options,
+            OraclePriceSource::Spot,
+        )
+    }
+
+    /// Plain L2 book for frontends: every resting and floating order bucketed by effective
+    /// price, at most `depth` levels per side.
+    pub fn get_l2_snapshot(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+        depth: usize,
+    ) -> L2OrderBook {
+        self.get_l2(
+            market_index,
+            market_type,
+            slot,
+            oracle_price_data,
+            L2Options {
+                depth,
+                ..L2Options::default()
+            },
+        )
+    }
+
+    /// Every resting and floating limit order individually, best price first and in queue
+    /// order within a price. Floating orders are priced off whatever oracle price the
+    /// configured `PriceFallback` resolves to and left out if it gives none.
+    pub fn get_l3_snapshot(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+    ) -> L3OrderBook {
+        let oracle_price_data = self
+            .resolve_oracle_price(market_index, market_type, None)
+            .ok();
+        let side_orders = |side| {
+            let nodes = match &oracle_price_data {
+                Some(oracle_price_data) => {
+                    self.get_maker_nodes(market_index, market_type, side, slot, oracle_price_data)
+                }
+                None => self
+                    .get_side_list(market_index, market_type, DLOBNodeType::RestingLimit, side)
+                    .map_or_else(Vec::new, |node_list| {
+                        node_list
+                            .iter_priced(&OraclePriceData::default(), slot)
+                            .filter(|(_, node)| !node.is_base_filled())
+                            .collect()
+                    }),
+            };
+
+            nodes
+                .iter()
+                .filter_map(|(price, node)| {
+                    let order = node.order()?;
+                    Some(L3Order {
+                        price: *price,
+                        size: get_remaining_base(node),
+                        maker: *node.user_account()?,
+                        order_id: order.order_id,
+                    })
+                })
+                .collect()
+        };
+
+        L3OrderBook {
+            bids: side_orders(Side::Bid),
+            asks: side_orders(Side::Ask),
+        }
+    }
+
+    /// Every price level as `side,price,size,num_orders` CSV rows under a header, bids then
+    /// asks, each best to worst. Prices and sizes are raw PRICE_PRECISION and
+    /// BASE_PRECISION integers.
+    pub fn to_csv(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        oracle_price_data: &OraclePriceData,
+        slot: u64,
+    ) -> String {
+        let l2 = self.get_l2_snapshot(
+            market_index,
+            market_type,
+            slot,
+            oracle_price_data,
+            usize::MAX,
+        );
+
+        let mut csv = String::from("side,price,size,num_orders\n");
+        for (side, levels) in [("bid", &l2.bids), ("ask", &l2.asks)] {
+            for level in levels {
+                csv += &format!(
+                    "{},{},{},{}\n",
+                    side, level.price, level.size, level.num_orders
+                );
+            }
+        }
+        csv
+    }
+
+    /// `get_l2` with floating orders priced off `price_source` instead of always the spot
+    /// oracle price.
+    pub fn get_l2_with_price_source(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+        options: L2Options,
+        price_source: OraclePriceSource,
+    ) -> L2OrderBook {
+        let oracle_price_data = price_source.resolve(oracle_price_data);
+        let side_levels = |side| {
+            let nodes =
+                self.get_maker_nodes(market_index, market_type, side, slot, &oracle_price_data);
+            let mut levels = aggregate_levels(
+                nodes
+                    .iter()
+                    .map(|(price, node)| {
+                        let price = options.round_to_tick.map_or(*price, |tick_size| {
+                            round_price_to_tick(*price, tick_size, side)
+                        });
+                        (price, get_remaining_base(node))
+                    })
+                    .filter(|(_, remaining_base)| *remaining_base >= options.min_size),
+                options.depth,
+            );
+            if options.cumulative {
+                accumulate_levels(&mut levels);
+            }
+            levels
+        };
+
+        L2OrderBook {
+            bids: side_levels(Side::Bid),
+            asks: side_levels(Side::Ask),
+        }
+    }
+
+    /// Precomputes every market's full L2 into an immutable, shareable `FrozenDlob`.
+    /// Markets without an entry in `oracle_price_data` are left out since their floating
+    /// orders can't be priced.
+    pub fn freeze(
+        &self,
+        slot: u64,
+        oracle_price_data: &HashMap<MarketId, OraclePriceData>,
+    ) -> FrozenDlob {
+        let books = self
+            .get_market_ids()
+            .into_iter()
+            .filter_map(|market_id| {
+                let oracle_price_data = oracle_price_data.get(&market_id)?;
+                let l2 = self.get_l2(
+                    market_id.market_index,
+                    market_id.market_type,
+                    slot,
+                    oracle_price_data,
+                    L2Options::default(),
+                );
+                Some((market_id, l2))
+            })
+            .collect();
+
+        FrozenDlob { slot, books }
+    }
+
+    /// Every taking order in a market that crosses resting makers, with the makers that would
+    /// fill it. Makers are consumed as they are matched so the same resting base isn't
+    /// handed to two takers.
+    pub fn find_nodes_to_fill(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Vec<NodeToFill> {
+        self.find_nodes_to_fill_with_extra(market_index, market_type, slot, oracle_price_data, &[])
+    }
+
+    /// `find_nodes_to_fill` with `extra_makers` merged into the resting side as if they were
+    /// in the book, e.g. synthetic makers for simulations. Book makers win price ties.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, oracle_price_data, extra_makers))
+    )]
+    pub fn find_nodes_to_fill_with_extra(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+        extra_makers: &[Arc<dyn DLOBNode>],
+    ) -> Vec<NodeToFill> {
+        let mut nodes_to_fill = vec![];
+
+        for (taker_side, maker_side) in [(Side::Bid, Side::Ask), (Side::Ask, Side::Bid)] {
+            let mut makers = self.get_maker_nodes(
+                market_index,
+                market_type,
+                maker_side,
+                slot,
+                oracle_price_data,
+            );
+            makers.extend(
+                self.get_vamm_node(market_index, market_type, maker_side)
+                    .map(|vamm_node| (vamm_node.get_price(oracle_price_data, slot), vamm_node)),
+            );
+            makers.extend(
+                extra_makers
+                    .iter()
+                    .filter(|maker| {
+                        maker.order().map_or(false, |order| {
+                            Side::from_direction(order.direction) == maker_side
+                        })
+                    })
+                    .map(|maker| (maker.get_price(oracle_price_data, slot), maker.clone())),
+            );
+            sort_best_first(&mut makers, maker_side);
+
+            let mut makers: Vec<(i128, Arc<dyn DLOBNode>, u64)> = makers
+                .into_iter()
+                .map(|(price, node)| {
+                    let remaining_base = get_remaining_base(&node);
+                    (price, node, remaining_base)
+                })
+                .collect();
+
+            for taker in self.get_taker_nodes(market_index, market_type, taker_side) {
+                if taker.is_base_filled() {
+                    continue;
+                }
+
+                let mut taker_price = get_taker_price(&taker, oracle_price_data, slot);
+                if taker.order().map_or(false, |order| order.is_market_order()) {
+                    if let Some(market_price_band) = self.market_price_band {
+                        taker_price = Some(apply_price_band(
+                            taker_side,
+                            taker_price,
+                            oracle_price_data,
+                            market_price_band,
+                        ));
+                    }
+                }
+                let mut taker_base = get_remaining_base(&taker);
+                let mut maker_nodes = vec![];
+
+                for (maker_price, maker, remaining_base) in makers.iter_mut() {
+                    if taker_base == 0 || !is_crossing(taker_side, taker_price, *maker_price) {
+                        break;
+                    }
+                    if *remaining_base == 0 || maker.user_account() == taker.user_account() {
+                        continue;
+                    }
+
+                    let fill_base = taker_base.min(*remaining_base);
+                    taker_base -= fill_base;
+                    *remaining_base -= fill_base;
+                    maker_nodes.push(maker.clone());
+                }
+
+                if !maker_nodes.is_empty() {
+                    dlob_trace!(
+                        order_id = ?taker.order().map(|order| order.order_id),
+                        ?taker_side,
+                        maker_count = maker_nodes.len(),
+                        "taker crosses makers"
+                    );
+                    nodes_to_fill.push(NodeToFill {
+                        node: taker,
+                        maker_nodes,
+                    });
+                }
+            }
+        }
+
+        nodes_to_fill
+    }
+
+    /// Every (taker, maker) pair that would trade in a market, in fill priority order: takers
+    /// in queue order, each with the makers it crosses best price first. Partially filled
+    /// nodes only contribute their remaining base.
+    pub fn find_crossing_nodes(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Vec<(Arc<dyn DLOBNode>, Arc<dyn DLOBNode>)> {
+        self.find_nodes_to_fill(market_index, market_type, slot, oracle_price_data)
+            .into_iter()
+            .flat_map(|node_to_fill| {
+                let taker = node_to_fill.node;
+                node_to_fill
+                    .maker_nodes
+                    .into_iter()
+                    .map(move |maker| (taker.clone(), maker))
+            })
+            .collect()
+    }
+
+    /// Pairs each taking order in a market with the best resting maker it crosses, or with the
+    /// vAMM when only the fallback price crosses. Makers are consumed as they are matched so
+    /// the same resting base isn't routed to two takers.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, oracle_price_data))
+    )]
+    pub fn route_fills(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+        fallback_bid: Option<i128>,
+        fallback_ask: Option<i128>,
+    ) -> FillRoute {
+        let mut fill_route = FillRoute::default();
+
+        for (taker_side, maker_side, fallback_price) in [
+            (Side::Bid, Side::Ask, fallback_ask),
+            (Side::Ask, Side::Bid, fallback_bid),
+        ] {
+            let mut makers: Vec<(i128, Arc<dyn DLOBNode>, u64)> = self
+                .get_maker_nodes(
+                    market_index,
+                    market_type,
+                    maker_side,
+                    slot,
+                    oracle_price_data,
+                )
+                .into_iter()
+                .map(|(price, node)| {
+                    let remaining_base = get_remaining_base(&node);
+                    (price, node, remaining_base)
+                })
+                .collect();
+
+            for taker in self.get_taker_nodes(market_index, market_type, taker_side) {
+                if taker.is_base_filled() {
+                    continue;
+                }
+
+                let taker_price = get_taker_price(&taker, oracle_price_data, slot);
+                let mut taker_base = get_remaining_base(&taker);
+
+                let maker = makers
+                    .iter_mut()
+                    .filter(|(_, maker, remaining_base)| {
+                        *remaining_base > 0 && maker.user_account() != taker.user_account()
+                    })
+                    .take_while(|(maker_price, _, _)| {
+                        is_crossing(taker_side, taker_price, *maker_price)
+                    })
+                    .next();
+
+                if let Some((_, maker, remaining_base)) = maker {
+                    taker_base = taker_base.min(*remaining_base);
+                    *remaining_base -= taker_base;
+                    dlob_trace!(
+                        order_id = ?taker.order().map(|order| order.order_id),
+                        maker_order_id = ?maker.order().map(|order| order.order_id),
+                        "routed taker to maker"
+                    );
+                    fill_route.against_makers.push((taker, maker.clone()));
+                } else if fallback_price
+                    .map_or(false, |price| is_crossing(taker_side, taker_price, price))
+                {
+                    dlob_trace!(
+                        order_id = ?taker.order().map(|order| order.order_id),
+                        "routed taker to vamm"
+                    );
+                    fill_route.against_vamm.push(taker);
+                }
+            }
+        }
+
+        fill_route
+    }
+
+    /// Cheap check for whether a taker of `direction` could get `base_amount` filled from
+    /// resting liquidity plus the base the opposite fallback (vAMM) quote can absorb. A
+    /// fallback with a non-positive price, or quotes that are crossed (ask below bid, i.e.
+    /// passed on the wrong side), contribute nothing. Stops walking the book as soon as
+    /// enough base is found.
+    #[allow(clippy::too_many_arguments)]
+    pub fn can_fill(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        direction: PositionDirection,
+        base_amount: u64,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+        fallback_bid: Option<FallbackQuote>,
+        fallback_ask: Option<FallbackQuote>,
+    ) -> bool {
+        let (maker_side, fallback) = match Side::from_direction(direction) {
+            Side::Bid => (Side::Ask, fallback_ask),
+            Side::Ask => (Side::Bid, fallback_bid),
+        };
+        let crossed = matches!(
+            (fallback_bid, fallback_ask),
+            (Some(bid), Some(ask)) if ask.price < bid.price
+        );
+        let fallback_base = fallback
+            .filter(|quote| quote.price > 0 && !crossed)
+            .map_or(0, |quote| quote.base_asset_amount);
+        if fallback_base > 0 && fallback_base >= base_amount {
+            return true;
+        }
+
+        let mut available_base = fallback_base;
+        [DLOBNodeType::RestingLimit, DLOBNodeType::FloatingLimit]
+            .into_iter()
+            .filter_map(|node_type| {
+                self.get_side_list(market_index, market_type, node_type, maker_side)
+            })
+            .flat_map(|node_list| node_list.iter_priced(oracle_price_data, slot))
+            .filter(|(price, _)| *price > 0)
+            .any(|(_, node)| {
+                available_base = available_base.saturating_add(get_remaining_base(&node));
+                available_base >= base_amount
+            })
+    }
+
+    /// Whether `order`, once its trigger fires, would immediately cross the resting book or
+    /// the fallback (vAMM) price on the opposite side, so keepers can bundle trigger + fill.
+    pub fn trigger_would_fill(
+        &self,
+        order: &Order,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+        fallback_bid: Option<i128>,
+        fallback_ask: Option<i128>,
+    ) -> bool {
+        if !order.must_be_triggered() {
+            return false;
+        }
+
+        let taker_side = Side::from_direction(order.direction);
+        let (maker_side, fallback_price) = match taker_side {
+            Side::Bid => (Side::Ask, fallback_ask),
+            Side::Ask => (Side::Bid, fallback_bid),
+        };
+
+        let best_maker_price = self
+            .get_maker_nodes(
+                order.market_index,
+                order.market_type.into(),
+                maker_side,
+                slot,
+                oracle_price_data,
+            )
+            .into_iter()
+            .next()
+            .map(|(price, _)| price);
+
+        // trigger market orders without a price take whatever liquidity is there
+        let taker_price = if order.price > 0 {
+            Some(order.price as i128)
+        } else {
+            None
+        };
+
+        [best_maker_price, fallback_price]
+            .into_iter()
+            .flatten()
+            .any(|maker_price| is_crossing(taker_side, taker_price, maker_price))
+    }
+
+    /// Records the current book mid for `order` so its realized spread can be measured
+    /// later. Returns the recorded mid, or `None` if the book has no mid.
+    #[cfg(feature = "analytics")]
+    pub fn record_placement_mid(
+        &mut self,
+        order: &Order,
+        user_account: &Pubkey,
+        oracle_price_data: &OraclePriceData,
+        slot: u64,
+    ) -> Option<i128> {
+        let market_id = MarketId::new(order.market_index, order.market_type.into());
+        let mid = self.get_mid(
+            market_id.market_index,
+            market_id.market_type,
+            MidSource::Book,
+            oracle_price_data,
+            slot,
+        )?;
+
+        self.placement_mids.insert(
+            get_order_signature(order.order_id, user_account),
+            PlacementMid {
+                market_id,
+                side: Side::from_direction(order.direction),
+                mid,
+            },
+        );
+
+        Some(mid)
+    }
+
+    /// How far the book mid has moved since the order was placed, signed so a move in the
+    /// order's favour (up for bids, down for asks) is positive. `None` if no placement mid
+    /// was recorded or the book currently has no mid.
+    #[cfg(feature = "analytics")]
+    pub fn realized_spread(
+        &self,
+        order_signature: &str,
+        oracle_price_data: &OraclePriceData,
+        slot: u64,
+    ) -> Option<i128> {
+        let placement = self.placement_mids.get(order_signature)?;
+        let mid = self.get_mid(
+            placement.market_id.market_index,
+            placement.market_id.market_type,
+            MidSource::Book,
+            oracle_price_data,
+            slot,
+        )?;
+
+        Some(match placement.side {
+            Side::Bid => mid - placement.mid,
+            Side::Ask => placement.mid - mid,
+        })
+    }
+
+    /// Removes every order whose signature is in `order_signatures`, across all markets, in
+    /// one pass over the book. Returns the number of nodes removed.
+    pub fn remove_signatures(&mut self, order_signatures: &HashSet<String>, slot: u64) -> usize {
+        self.clamp_slot(slot);
+
+        for open_orders in self.open_orders.values_mut() {
+            open_orders.retain(|order_signature| !order_signatures.contains(order_signature));
+        }
+
+        let mut removed = 0;
+        for market_node_lists_map in self.order_lists.values_mut() {
+            for market_node_lists in market_node_lists_map.values_mut() {
+                removed += retain_market_node_lists(market_node_lists, |node| {
+                    match (node.order(), node.user_account()) {
+                        (Some(order), Some(user_account)) => !order_signatures
+                            .contains(&get_order_signature(order.order_id, user_account)),
+                        _ => true,
+                    }
+                });
+            }
+        }
+
+        removed
+    }
+
+    /// Best opposing maker a taker in `taker_direction` would hit: the best ask for longs
+    /// and the best bid for shorts.
+    pub fn get_best_for_taker(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        taker_direction: PositionDirection,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<Arc<dyn DLOBNode>> {
+        let maker_side = match Side::from_direction(taker_direction) {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+
+        self.get_maker_nodes(
+            market_index,
+            market_type,
+            maker_side,
+            slot,
+            oracle_price_data,
+        )
+        .into_iter()
+        .next()
+        .map(|(_, node)| node)
+    }
+
+    /// Best maker bid and ask prices, counting only the makers allowed by `maker_mode`.
+    pub fn get_effective_bid_ask(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+        maker_mode: MakerMode,
+    ) -> (Option<i128>, Option<i128>) {
+        let best_price = |side| {
+            self.get_maker_nodes(market_index, market_type, side, slot, oracle_price_data)
+                .into_iter()
+                .find(|(_, node)| match maker_mode {
+                    MakerMode::All => true,
+                    MakerMode::ProtectedOnly => node.user_account().map_or(false, |user_account| {
+                        self.protected_makers.contains(user_account)
+                    }),
+                })
+                .map(|(price, _)| price)
+        };
+
+        (best_price(Side::Bid), best_price(Side::Ask))
+    }
+
+    /// Trigger orders in a market that have fired but not filled at all yet, so keepers can
+    /// re-evaluate them.
+    pub fn get_triggered_unfilled(&self, market_index: u16, market_type: MarketType) -> Vec<Order> {
+        let market_id = MarketId::new(market_index, market_type);
+
+        self.iter_labeled_lists()
+            .filter(|(list_market_id, node_type, _, _)| {
+                *list_market_id == market_id && *node_type != DLOBNodeType::Trigger
+            })
+            .flat_map(|(_, _, _, node_list)| node_list.iter())
+            .filter_map(|node| {
+                node.as_order_node()
+                    .filter(|order_node| {
+                        order_node.have_trigger && order_node.order.base_asset_amount_filled == 0
+                    })
+                    .map(|order_node| order_node.order)
+            })
+            .collect()
+    }
+
+    /// Untriggered trigger orders in a market with how far the oracle has to move before
+    /// each fires, closest first. A negative distance means the order is already triggerable.
+    pub fn trigger_distances(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        oracle_price_data: &OraclePriceData,
+    ) -> Vec<(Order, i128)> {
+        let market_id = MarketId::new(market_index, market_type);
+        let oracle_price = oracle_price_data.price as i128;
+
+        let mut distances: Vec<(Order, i128)> = self
+            .iter_labeled_lists()
+            .filter(|(list_market_id, node_type, _, _)| {
+                *list_market_id == market_id && *node_type == DLOBNodeType::Trigger
+            })
+            .flat_map(|(_, _, _, node_list)| node_list.iter())
+            .filter_map(|node| node.order().copied())
+            .map(|order| {
+                let trigger_price = order.trigger_price as i128;
+                let distance = match order.trigger_condition {
+                    OrderTriggerCondition::Above => trigger_price - oracle_price,
+                    _ => oracle_price - trigger_price,
+                };
+                (order, distance)
+            })
+            .collect();
+
+        distances.sort_by_key(|(_, distance)| *distance);
+        distances
+    }
+
+    /// Number of maker orders, and their remaining base, queued ahead of the given order
+    /// on its side of the book.
+    pub fn queue_position(
+        &self,
+        order_id: u32,
+        user_account: Pubkey,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Option<(usize, u64)> {
+        let order_signature = get_order_signature(order_id, &user_account);
+
+        [Side::Bid, Side::Ask].into_iter().find_map(|side| {
+            let nodes =
+                self.get_maker_nodes(market_index, market_type, side, slot, oracle_price_data);
+            let position =
+                nodes
+                    .iter()
+                    .position(|(_, node)| match (node.order(), node.user_account()) {
+                        (Some(order), Some(user)) => {
+                            get_order_signature(order.order_id, user) == order_signature
+                        }
+                        _ => false,
+                    })?;
+            let base_ahead = nodes[..position]
+                .iter()
+                .map(|(_, node)| get_remaining_base(node))
+                .fold(0_u64, |total, base| total.saturating_add(base));
+
+            Some((position, base_ahead))
+        })
+    }
+
+    /// Notional depth of one side of the book: the sum of each maker order's remaining base
+    /// times its effective price, in QUOTE_PRECISION.
+    pub fn total_quote_liquidity(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        side: Side,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> u128 {
+        self.get_maker_nodes(market_index, market_type, side, slot, oracle_price_data)
+            .into_iter()
+            .map(|(_, node)| node.remaining_notional(oracle_price_data, slot))
+            .fold(0_u128, |total, quote| total.saturating_add(quote))
+    }
+
+    /// Resting and floating limit nodes on one side whose effective price is within
+    /// `price_range`, best price first.
+    pub fn get_orders_in_price_range(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        side: Side,
+        price_range: RangeInclusive<i128>,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Vec<Arc<dyn DLOBNode>> {
+        self.get_maker_nodes(market_index, market_type, side, slot, oracle_price_data)
+            .into_iter()
+            .filter(|(price, _)| price_range.contains(price))
+            .map(|(_, node)| node)
+            .collect()
+    }
+
+    /// Resting and floating limit nodes on one side whose effective price is exactly
+    /// `price`, in queue order. Tracking these over time is the basis for spotting orders
+    /// that keep reloading at the same level.
+    pub fn orders_at_price(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        side: Side,
+        price: i128,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Vec<Arc<dyn DLOBNode>> {
+        self.get_orders_in_price_range(
+            market_index,
+            market_type,
+            side,
+            price..=price,
+            slot,
+            oracle_price_data,
+        )
+    }
+
+    /// Market and taking limit nodes for one side of a market, in priority order.
+    fn get_taker_nodes(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        side: Side,
+    ) -> Vec<Arc<dyn DLOBNode>> {
+        [DLOBNodeType::Market, DLOBNodeType::TakingLimit]
+            .into_iter()
+            .filter_map(|node_type| self.get_side_list(market_index, market_type, node_type, side))
+            .flat_map(|node_list| node_list.iter())
+            .collect()
+    }
+
+    /// Resting and floating limit nodes for one side of a market paired with their effective
+    /// price, best price first.
+    fn get_maker_nodes(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        side: Side,
+        slot: u64,
+        oracle_price_data: &OraclePriceData,
+    ) -> Vec<(i128, Arc<dyn DLOBNode>)> {
+        let mut nodes: Vec<(i128, Arc<dyn DLOBNode>)> =
+            [DLOBNodeType::RestingLimit, DLOBNodeType::FloatingLimit]
+                .into_iter()
+                .filter_map(|node_type| {
+                    self.get_side_list(market_index, market_type, node_type, side)
+                })
+                .flat_map(|node_list| node_list.iter_priced(oracle_price_data, slot))
+                .filter(|(_, node)| !node.is_base_filled())
+                .collect();
+
+        sort_best_first(&mut nodes, side);
+
+        nodes
+    }
+
+    /// Resting limit bids, best price first, followed by the floating limit bids, highest
+    /// oracle offset first and then by slot and order id. `update_resting_limit_orders`
+    /// needs `&mut self`, so rather than calling it this merges in the taking limit orders
+    /// that have aged into resting orders by `slot`, which yields the same nodes without
+    /// mutating the book.
+    pub fn get_resting_limit_bids(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+    ) -> impl Iterator<Item = Arc<dyn DLOBNode>> + '_ {
+        self.get_resting_limit_nodes(market_index, market_type, Side::Bid, slot)
+    }
+
+    /// Ask side counterpart of `get_resting_limit_bids`.
+    pub fn get_resting_limit_asks(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+    ) -> impl Iterator<Item = Arc<dyn DLOBNode>> + '_ {
+        self.get_resting_limit_nodes(market_index, market_type, Side::Ask, slot)
+    }
+
+    /// Market and taking limit bids still in their auction window, in sequence (slot)
+    /// order, which is the sort value of the market lists and the order takers get filled
+    /// in. Taking limit orders that have aged into resting orders by `slot` are left out.
+    pub fn get_taking_bids(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+    ) -> impl Iterator<Item = Arc<dyn DLOBNode>> + '_ {
+        self.get_auction_nodes(market_index, market_type, Side::Bid, slot)
+    }
+
+    /// Ask side counterpart of `get_taking_bids`.
+    pub fn get_taking_asks(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        slot: u64,
+    ) -> impl Iterator<Item = Arc<dyn DLOBNode>> + '_ {
+        self.get_auction_nodes(market_index, market_type, Side::Ask, slot)
+    }
+
+    fn get_auction_nodes(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        side: Side,
+        slot: u64,
+    ) -> impl Iterator<Item = Arc<dyn DLOBNode>> + '_ {
+        let mut nodes: Vec<Arc<dyn DLOBNode>> = self
+            .get_taker_nodes(market_index, market_type, side)
+            .into_iter()
+            .filter(|node| {
+                !node.order().map_or(false, |order| {
+                    order.is_resting_limit_order(slot).unwrap_or(false)
+                })
+            })
+            .collect();
+        nodes.sort_by_key(|node| {
+            node.as_order_node()
+                .map_or(0, |order_node| order_node.sequence)
+        });
+
+        nodes.into_iter()
+    }
+
+    fn get_resting_limit_nodes(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        side: Side,
+        slot: u64,
+    ) -> impl Iterator<Item = Arc<dyn DLOBNode>> + '_ {
+        // resting and taking limit prices don't depend on the oracle
+        let oracle_price_data = OraclePriceData::default();
+        let matured_taking = self
+            .get_side_list(market_index, market_type, DLOBNodeType::TakingLimit, side)
+            .into_iter()
+            .flat_map(|node_list| node_list.iter_priced(&oracle_price_data, slot))
+            .filter(|(_, node)| {
+                node.order().map_or(false, |order| {
+                    order.is_resting_limit_order(slot).unwrap_or(false)
+                })
+            });
+        let mut resting: Vec<(i128, Arc<dyn DLOBNode>)> = self
+            .get_side_list(market_index, market_type, DLOBNodeType::RestingLimit, side)
+            .into_iter()
+            .flat_map(|node_list| node_list.iter_priced(&oracle_price_data, slot))
+            .chain(matured_taking)
+            .collect();
+        sort_best_first(&mut resting, side);
+
+        resting.into_iter().map(|(_, node)| node).chain(
+            self.get_side_list(market_index, market_type, DLOBNodeType::FloatingLimit, side)
+                .into_iter()
+                .flat_map(|node_list| node_list.iter()),
+        )
+    }
+
+    fn get_side_list(
+        &self,
+        market_index: u16,
+        market_type: MarketType,
+        node_type: DLOBNodeType,
+        side: Side,
+    ) -> Option<&NodeList> {
+        self.order_lists
+            .get(&market_type)
+            .and_then(|market_node_lists_map| market_node_lists_map.get(&market_index))
+            .and_then(|market_node_lists| market_node_lists.side_lists(node_type))
+            .map(|list| match side {
+                Side::Ask => &list.ask,
+                Side::Bid => &list.bid,
+            })
+    }
+
+    /// Every node list in the book, labelled with the market, node type and side (or trigger
+    /// condition) it holds.
+    pub fn iter_labeled_lists(
+        &self,
+    ) -> impl Iterator<Item = (MarketId, DLOBNodeType, OrderSubType, &NodeList)> {
+        self.order_lists
+            .iter()
+            .flat_map(|(market_type, market_node_lists_map)| {
+                market_node_lists_map
+                    .iter()
+                    .flat_map(move |(market_index, market_node_lists)| {
+                        let market_id = MarketId::new(*market_index, *market_type);
+                        market_node_lists.labeled_lists().into_iter().map(
+                            move |(node_type, sub_type, node_list)| {
+                                (market_id, node_type, sub_type, node_list)
+                            },
+                        )
+                    })
+            })
+    }
+
+    /// Signatures linked into any list but missing from that list's `node_map`, see
+    /// `NodeList::orphan_signatures`.
+    pub fn find_orphans(&self) -> Vec<String> {
+        self.iter_labeled_lists()
+            .flat_map(|(_, _, _, node_list)| node_list.orphan_signatures())
+            .collect()
+    }
+
+    /// Signatures held by more than one list, with the type of each list holding them,
+    /// sorted by signature. An order should only ever be in one list; duplicates point at a
+    /// missed removal when moving orders between lists.
+    pub fn find_duplicate_nodes(&self) -> Vec<(String, Vec<DLOBNodeType>)> {
+        let mut node_types_by_signature: BTreeMap<String, Vec<DLOBNodeType>> = BTreeMap::new();
+        for (_, node_type, _, node_list) in self.iter_labeled_lists() {
+            for order_signature in node_list.signatures() {
+                node_types_by_signature
+                    .entry(order_signature.to_string())
+                    .or_insert_with(Vec::new)
+                    .push(node_type.clone());
+            }
+        }
+
+        node_types_by_signature
+            .into_iter()
+            .filter(|(_, node_types)| node_types.len() > 1)
+            .collect()
+    }
+
+    /// Validates every node list in the book, see `NodeList::validate`.
+    pub fn validate(&self) -> DriftResult<()> {
+        for (_, _, _, node_list) in self.iter_labeled_lists() {
+            node_list.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Every order in the book belonging to `user_account`, by order id. Each order is
+    /// reported once even if it were somehow linked into more than one list.
+    pub fn get_orders_for_user(&self, user_account: &Pubkey) -> Vec<Order> {
+        let mut seen = HashSet::new();
+        let mut orders: Vec<Order> = self.with_node_lists(|node_lists| {
+            node_lists
+                .iter()
+                .flat_map(|node_list| node_list.iter())
+                .filter(|node| node.user_account() == Some(user_account))
+                .filter_map(|node| node.order().copied())
+                .filter(|order| seen.insert(get_order_signature(order.order_id, user_account)))
+                .collect()
+        });
+        orders.sort_by_key(|order| order.order_id);
+        orders
+    }
+
+    /// Runs `f` over borrowed references to every node list, for read-only passes that
+    /// shouldn't pay for the clones `get_node_lists` makes.
+    pub fn with_node_lists<R>(&self, f: impl FnOnce(&[&NodeList]) -> R) -> R {
+        let node_lists: Vec<&NodeList> = self
+            .iter_labeled_lists()
+            .map(|(_, _, _, node_list)| node_list)
+            .collect();
+        f(&node_lists)
     }
 
     pub fn get_node_lists(&self) -> Vec<NodeList> {
@@ -446,12 +3006,11 @@ impl DLOB {
             .get(&MarketType::Perp)
             .unwrap_or(&HashMap::new())
             .values()
-            .flat_map(|market_node_list| match market_node_list {
-                MarketNodeLists::RestingLimit(list) => vec![list.ask.clone(), list.bid.clone()],
-                MarketNodeLists::FloatingLimit(list) => vec![list.ask.clone(), list.bid.clone()],
-                MarketNodeLists::TakingLimit(list) => vec![list.ask.clone(), list.bid.clone()],
-                MarketNodeLists::Market(list) => vec![list.ask.clone(), list.bid.clone()],
-                MarketNodeLists::Trigger(list) => vec![list.above.clone(), list.below.clone()],
+            .flat_map(|market_node_lists| {
+                market_node_lists
+                    .labeled_lists()
+                    .into_iter()
+                    .map(|(_, _, node_list)| node_list.clone())
             })
             .collect();
 
@@ -460,12 +3019,11 @@ impl DLOB {
             .get(&MarketType::Spot)
             .unwrap_or(&HashMap::new())
             .values()
-            .flat_map(|market_node_list| match market_node_list {
-                MarketNodeLists::RestingLimit(list) => vec![list.ask.clone(), list.bid.clone()],
-                MarketNodeLists::FloatingLimit(list) => vec![list.ask.clone(), list.bid.clone()],
-                MarketNodeLists::TakingLimit(list) => vec![list.ask.clone(), list.bid.clone()],
-                MarketNodeLists::Market(list) => vec![list.ask.clone(), list.bid.clone()],
-                MarketNodeLists::Trigger(list) => vec![list.above.clone(), list.below.clone()],
+            .flat_map(|market_node_lists| {
+                market_node_lists
+                    .labeled_lists()
+                    .into_iter()
+                    .map(|(_, _, node_list)| node_list.clone())
             })
             .collect();
 
@@ -476,6 +3034,169 @@ impl DLOB {
     }
 }
 
+fn clear_market_node_lists(market_node_lists: &mut MarketNodeLists) {
+    for node_list in market_node_lists.lists_mut() {
+        node_list.clear();
+    }
+}
+
+fn retain_market_node_lists<F>(market_node_lists: &mut MarketNodeLists, mut f: F) -> usize
+where
+    F: FnMut(&Arc<dyn DLOBNode>) -> bool,
+{
+    market_node_lists
+        .lists_mut()
+        .into_iter()
+        .map(|node_list| node_list.retain(&mut f))
+        .sum()
+}
+
+/// `price` moved onto a multiple of `tick_size`, down for bids and up for asks so a level
+/// never looks better than its orders. A zero tick leaves it as is.
+fn round_price_to_tick(price: i128, tick_size: u64, side: Side) -> i128 {
+    if tick_size == 0 {
+        return price;
+    }
+
+    let tick_size = tick_size as i128;
+    let rounded_down = price.div_euclid(tick_size) * tick_size;
+    match side {
+        Side::Bid => rounded_down,
+        Side::Ask if rounded_down == price => price,
+        Side::Ask => rounded_down + tick_size,
+    }
+}
+
+/// Stable sort of priced nodes on `side`, best price first. Ties go to fixed-price orders
+/// before floating ones, then to the older order by slot, then keep their list order.
+fn sort_best_first(nodes: &mut [(i128, Arc<dyn DLOBNode>)], side: Side) {
+    nodes.sort_by(|(a_price, a), (b_price, b)| {
+        let by_price = match side {
+            Side::Bid => b_price.cmp(a_price),
+            Side::Ask => a_price.cmp(b_price),
+        };
+        by_price.then_with(|| tie_break_key(a).cmp(&tie_break_key(b)))
+    });
+}
+
+fn tie_break_key(node: &Arc<dyn DLOBNode>) -> (bool, u64) {
+    // nodes without an order, i.e. the vAMM, go behind every order at their price
+    node.order().map_or((true, u64::MAX), |order| {
+        (order.oracle_price_offset != 0, order.slot)
+    })
+}
+
+/// A taker's limit price, or `None` for market orders that take any price.
+fn get_taker_price(
+    taker: &Arc<dyn DLOBNode>,
+    oracle_price_data: &OraclePriceData,
+    slot: u64,
+) -> Option<i128> {
+    let price = taker.get_price(oracle_price_data, slot);
+    if price > 0 {
+        Some(price)
+    } else {
+        None
+    }
+}
+
+/// Caps a market taker's price to `market_price_band` (in MARGIN_PRECISION) through the
+/// oracle price, so it can't sweep makers priced outside the protocol's band.
+fn apply_price_band(
+    taker_side: Side,
+    taker_price: Option<i128>,
+    oracle_price_data: &OraclePriceData,
+    market_price_band: u32,
+) -> i128 {
+    let band = market_price_band.min(i32::MAX as u32) as i32;
+    let oracle_price = oracle_price_data.price as i128;
+    match taker_side {
+        Side::Bid => {
+            let max_price = apply_bps(oracle_price, band);
+            taker_price.map_or(max_price, |price| price.min(max_price))
+        }
+        Side::Ask => {
+            let min_price = apply_bps(oracle_price, -band);
+            taker_price.map_or(min_price, |price| price.max(min_price))
+        }
+    }
+}
+
+/// Whether a taker on `taker_side` with an optional limit price would trade against a
+/// maker at `maker_price`.
+fn is_crossing(taker_side: Side, taker_price: Option<i128>, maker_price: i128) -> bool {
+    match (taker_side, taker_price) {
+        (_, None) => true,
+        (Side::Bid, Some(taker_price)) => taker_price >= maker_price,
+        (Side::Ask, Some(taker_price)) => taker_price <= maker_price,
+    }
+}
+
+fn validate_order_fields(order: &Order) -> DriftResult<()> {
+    validate!(
+        order.base_asset_amount > 0,
+        ErrorCode::InvalidOrderSizeTooSmall,
+        "order {} has zero base_asset_amount",
+        order.order_id
+    )?;
+
+    validate!(
+        order.base_asset_amount_filled <= order.base_asset_amount,
+        ErrorCode::InvalidOrder,
+        "order {} filled {} more than its base_asset_amount {}",
+        order.order_id,
+        order.base_asset_amount_filled,
+        order.base_asset_amount
+    )?;
+
+    if order.is_limit_order() {
+        validate!(
+            order.price > 0 || order.oracle_price_offset != 0,
+            ErrorCode::InvalidOrderLimitPrice,
+            "limit order {} has neither a price nor an oracle_price_offset",
+            order.order_id
+        )?;
+    } else {
+        validate!(
+            order.oracle_price_offset == 0 || order.order_type == OrderType::Oracle,
+            ErrorCode::InvalidOrderOracleOffset,
+            "market order {} has an oracle_price_offset",
+            order.order_id
+        )?;
+    }
+
+    if order.must_be_triggered() {
+        validate!(
+            order.trigger_price > 0,
+            ErrorCode::InvalidOrderTrigger,
+            "trigger order {} has no trigger_price",
+            order.order_id
+        )?;
+    }
+
+    validate!(
+        order.market_index <= MAX_PLAUSIBLE_MARKET_INDEX,
+        ErrorCode::InvalidOrder,
+        "order {} has implausible market_index {}",
+        order.order_id,
+        order.market_index
+    )?;
+
+    Ok(())
+}
+
+fn get_remaining_base(node: &Arc<dyn DLOBNode>) -> u64 {
+    if node.is_vamm_node() {
+        return u64::MAX;
+    }
+    node.order().map_or(0, |order| {
+        order
+            .base_asset_amount
+            .saturating_sub(order.base_asset_amount_filled)
+    })
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum OrderSubType {
     Trigger(OrderTriggerCondition),
     Side(Side),
@@ -488,19 +3209,37 @@ fn determine_sub_type(order: &Order, is_inactive_trigger_order: bool) -> OrderSu
             _ => OrderTriggerCondition::Below,
         })
     } else {
-        OrderSubType::Side(match order.direction {
-            PositionDirection::Long => Side::Bid,
-            _ => Side::Ask,
-        })
+        OrderSubType::Side(Side::from_direction(order.direction))
+    }
+}
+
+/// Direction the `node_type` list for `side` is sorted in, so it iterates best first:
+/// bids highest price first and asks lowest first. Market lists are FIFO by sequence on
+/// both sides. Trigger lists use the ask direction for `above` and the bid direction for
+/// `below`.
+pub fn sort_direction_for(node_type: DLOBNodeType, side: Side) -> SortDirection {
+    match (node_type, side) {
+        (DLOBNodeType::Market, _) => SortDirection::Asc,
+        (_, Side::Bid) => SortDirection::Desc,
+        (_, Side::Ask) => SortDirection::Asc,
     }
 }
 
+/// Which market and list `order` belongs in at `slot`.
+fn list_index(order: &Order, slot: u64) -> (MarketType, u16, DLOBNodeType, OrderSubType) {
+    let node_type = determine_node_type(order, slot);
+    let is_inactive_trigger_order = node_type == DLOBNodeType::Trigger;
+    (
+        order.market_type.into(),
+        order.market_index,
+        node_type,
+        determine_sub_type(order, is_inactive_trigger_order),
+    )
+}
+
 fn determine_node_type(order: &Order, slot: u64) -> DLOBNodeType {
-    if matches!(
-        order.trigger_condition,
-        OrderTriggerCondition::TriggeredAbove | OrderTriggerCondition::TriggeredBelow
-    ) && order.must_be_triggered()
-    {
+    // only trigger orders still waiting on their trigger sit in the trigger lists
+    if order.must_be_triggered() && !order.triggered() {
         DLOBNodeType::Trigger
     } else if matches!(
         order.order_type,
@@ -509,7 +3248,7 @@ fn determine_node_type(order: &Order, slot: u64) -> DLOBNodeType {
         DLOBNodeType::Market
     } else if order.oracle_price_offset != 0 {
         DLOBNodeType::FloatingLimit
-    } else if order.is_resting_limit_order(slot).unwrap() {
+    } else if order.is_resting_limit_order(slot).unwrap_or(false) {
         DLOBNodeType::RestingLimit
     } else {
         DLOBNodeType::TakingLimit
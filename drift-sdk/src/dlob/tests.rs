@@ -0,0 +1,3201 @@
+use std::collections::HashSet;
+
+use anchor_lang::prelude::{AccountLoader, Pubkey};
+use anchor_lang::Owner;
+use drift::controller::position::PositionDirection;
+use drift::error::ErrorCode;
+use drift::math::constants::{BASE_PRECISION_U64, PRICE_PRECISION_U64, QUOTE_PRECISION};
+use drift::state::events::{get_order_action_record, OrderAction, OrderActionExplanation};
+use drift::state::oracle::OraclePriceData;
+use drift::state::user::{Order, OrderStatus, OrderTriggerCondition, OrderType, User};
+use drift::state::user_map::UserMap;
+
+use crate::dlob::{
+    determine_node_type, determine_sub_type, sort_direction_for, FallbackQuote, InsertPreview,
+    MakerMode, MarketId, MarketType, MidSource, OrderSubType, PriceFallback, Side, TickSizePolicy,
+    TopOfBook, DLOB, MAX_PLAUSIBLE_MARKET_INDEX,
+};
+use crate::dlob_node::{create_node, DLOBNode, DLOBNodeType};
+use crate::l2::{L2Level, L2Options};
+use crate::l3::L3Order;
+use crate::node_list::{get_order_signature, SortDirection};
+use crate::test_utils::{
+    create_account_info, get_anchor_account_bytes, CountingNodeFactory, OrderBuilder,
+};
+
+fn resting_limit_order(order_id: u32, direction: PositionDirection, price: u64) -> Order {
+    OrderBuilder::new()
+        .order_id(order_id)
+        .direction(direction)
+        .price(price)
+        .post_only(true)
+        .build()
+}
+
+/// Order action record naming `order` as the taker order of `user`, as the program emits
+/// for cancels, expiries, triggers and taker fills.
+fn order_action_record(
+    action: OrderAction,
+    user: Pubkey,
+    order: Order,
+) -> drift::state::events::OrderActionRecord {
+    get_order_action_record(
+        0,
+        action,
+        OrderActionExplanation::None,
+        order.market_index,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(user),
+        Some(order),
+        None,
+        None,
+        0,
+    )
+    .unwrap()
+}
+
+#[test]
+fn snapshot_market_round_trip() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    let bid = Order {
+        status: OrderStatus::Open,
+        order_type: OrderType::Limit,
+        market_type: drift::state::user::MarketType::Perp,
+        market_index: 0,
+        order_id: 1,
+        direction: PositionDirection::Long,
+        price: 99 * PRICE_PRECISION_U64,
+        base_asset_amount: BASE_PRECISION_U64,
+        post_only: true,
+        ..Order::default()
+    };
+    let ask = Order {
+        order_id: 2,
+        direction: PositionDirection::Short,
+        price: 101 * PRICE_PRECISION_U64,
+        ..bid
+    };
+    let other_market = Order {
+        order_id: 3,
+        market_index: 1,
+        ..bid
+    };
+
+    dlob.insert_order(bid, user, 0).unwrap();
+    dlob.insert_order(ask, user, 0).unwrap();
+    dlob.insert_order(other_market, user, 0).unwrap();
+
+    let snapshot = dlob.snapshot_market(0, MarketType::Perp);
+    assert_eq!(snapshot.orders.len(), 2);
+    assert!(snapshot
+        .orders
+        .iter()
+        .all(|dlob_order| dlob_order.order.market_index == 0));
+
+    #[cfg(feature = "serde")]
+    let snapshot: crate::dlob::MarketSnapshot =
+        serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+
+    // the replica still holds an expiring copy of the bid and an order the source dropped
+    let mut replica = DLOB::new().unwrap();
+    replica
+        .insert_order(Order { max_ts: 100, ..bid }, user, 0)
+        .unwrap();
+    replica
+        .insert_order(Order { order_id: 9, ..bid }, user, 0)
+        .unwrap();
+    replica.restore_market(snapshot, 0).unwrap();
+
+    let restored_ids = |replica: &DLOB| {
+        let mut order_ids: Vec<u32> = replica
+            .snapshot_market(0, MarketType::Perp)
+            .orders
+            .iter()
+            .map(|dlob_order| dlob_order.order.order_id)
+            .collect();
+        order_ids.sort_unstable();
+        order_ids
+    };
+    assert_eq!(restored_ids(&replica), vec![1, 2]);
+    assert!(replica.expire_orders(101).unwrap().is_empty());
+    assert_eq!(restored_ids(&replica), vec![1, 2]);
+    assert!(replica
+        .snapshot_market(1, MarketType::Perp)
+        .orders
+        .is_empty());
+}
+
+#[test]
+fn worst_bid_ask_on_three_level_book() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    for (order_id, price) in [(1, 99), (2, 97), (3, 98)] {
+        let order = resting_limit_order(
+            order_id,
+            PositionDirection::Long,
+            price * PRICE_PRECISION_U64,
+        );
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+    for (order_id, price) in [(4, 103), (5, 101), (6, 102)] {
+        let order = resting_limit_order(
+            order_id,
+            PositionDirection::Short,
+            price * PRICE_PRECISION_U64,
+        );
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    assert_eq!(
+        dlob.get_worst_bid(0, MarketType::Perp, 0, &oracle_price_data),
+        Some(97 * PRICE_PRECISION_U64 as i128)
+    );
+    assert_eq!(
+        dlob.get_worst_ask(0, MarketType::Perp, 0, &oracle_price_data),
+        Some(103 * PRICE_PRECISION_U64 as i128)
+    );
+    assert_eq!(
+        dlob.get_worst_bid(1, MarketType::Perp, 0, &oracle_price_data),
+        None
+    );
+}
+
+#[test]
+fn worst_bid_ask_with_floating_orders_inserted_out_of_offset_order() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData {
+        price: 100 * PRICE_PRECISION_U64 as i64,
+        ..OraclePriceData::default_usd()
+    };
+
+    let floating_order = |order_id: u32, direction: PositionDirection, offset: i32| {
+        OrderBuilder::new()
+            .order_id(order_id)
+            .direction(direction)
+            .oracle_price_offset(offset * PRICE_PRECISION_U64 as i32)
+            .post_only(true)
+            .slot(order_id as u64)
+            .build()
+    };
+    for (order_id, offset) in [(1, -1), (2, -3), (3, -2)] {
+        dlob.insert_order(
+            floating_order(order_id, PositionDirection::Long, offset),
+            user,
+            0,
+        )
+        .unwrap();
+    }
+    for (order_id, offset) in [(4, 3), (5, 1), (6, 2)] {
+        dlob.insert_order(
+            floating_order(order_id, PositionDirection::Short, offset),
+            user,
+            0,
+        )
+        .unwrap();
+    }
+
+    assert_eq!(
+        dlob.get_worst_bid(0, MarketType::Perp, 0, &oracle_price_data),
+        Some(97 * PRICE_PRECISION_U64 as i128)
+    );
+    assert_eq!(
+        dlob.get_worst_ask(0, MarketType::Perp, 0, &oracle_price_data),
+        Some(103 * PRICE_PRECISION_U64 as i128)
+    );
+}
+
+#[test]
+fn crossed_base_volume_matches_crossing_levels() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let bid_high = Order {
+        base_asset_amount: 2 * BASE_PRECISION_U64,
+        ..resting_limit_order(1, PositionDirection::Long, 102 * PRICE_PRECISION_U64)
+    };
+    let bid_low = resting_limit_order(2, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+    let ask_low = resting_limit_order(3, PositionDirection::Short, 100 * PRICE_PRECISION_U64);
+    let ask_mid = resting_limit_order(4, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    let ask_high = resting_limit_order(5, PositionDirection::Short, 103 * PRICE_PRECISION_U64);
+
+    for order in [bid_high, bid_low, ask_low, ask_mid, ask_high] {
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    // the 102 bid takes both the 100 and 101 asks, the 99 bid crosses nothing
+    assert_eq!(
+        dlob.crossed_base_volume(0, MarketType::Perp, 0, &oracle_price_data),
+        2 * BASE_PRECISION_U64
+    );
+    assert_eq!(
+        dlob.crossed_base_volume(1, MarketType::Perp, 0, &oracle_price_data),
+        0
+    );
+}
+
+#[test]
+fn insert_order_validated_accepts_well_formed_order() {
+    let mut dlob = DLOB::new().unwrap();
+    let order = resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+
+    assert_eq!(
+        dlob.insert_order_validated(order, Pubkey::new_unique(), 0),
+        Ok(())
+    );
+}
+
+#[test]
+fn insert_order_validated_rejects_zero_base() {
+    let mut dlob = DLOB::new().unwrap();
+    let order = Order {
+        base_asset_amount: 0,
+        ..resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+
+    assert_eq!(
+        dlob.insert_order_validated(order, Pubkey::new_unique(), 0),
+        Err(ErrorCode::InvalidOrderSizeTooSmall)
+    );
+}
+
+#[test]
+fn insert_order_validated_rejects_overfilled_order() {
+    let mut dlob = DLOB::new().unwrap();
+    let order = Order {
+        base_asset_amount_filled: 2 * BASE_PRECISION_U64,
+        ..resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+
+    assert_eq!(
+        dlob.insert_order_validated(order, Pubkey::new_unique(), 0),
+        Err(ErrorCode::InvalidOrder)
+    );
+}
+
+#[test]
+fn insert_order_validated_rejects_limit_without_price() {
+    let mut dlob = DLOB::new().unwrap();
+    let order = resting_limit_order(1, PositionDirection::Long, 0);
+
+    assert_eq!(
+        dlob.insert_order_validated(order, Pubkey::new_unique(), 0),
+        Err(ErrorCode::InvalidOrderLimitPrice)
+    );
+
+    let floating = Order {
+        oracle_price_offset: -(PRICE_PRECISION_U64 as i32),
+        ..order
+    };
+    assert_eq!(
+        dlob.insert_order_validated(floating, Pubkey::new_unique(), 0),
+        Ok(())
+    );
+}
+
+#[test]
+fn insert_order_validated_rejects_market_order_with_offset() {
+    let mut dlob = DLOB::new().unwrap();
+    let order = Order {
+        order_type: OrderType::Market,
+        oracle_price_offset: PRICE_PRECISION_U64 as i32,
+        post_only: false,
+        ..resting_limit_order(1, PositionDirection::Long, 0)
+    };
+
+    assert_eq!(
+        dlob.insert_order_validated(order, Pubkey::new_unique(), 0),
+        Err(ErrorCode::InvalidOrderOracleOffset)
+    );
+}
+
+#[test]
+fn insert_order_validated_rejects_trigger_without_trigger_price() {
+    let mut dlob = DLOB::new().unwrap();
+    let order = Order {
+        order_type: OrderType::TriggerMarket,
+        trigger_condition: OrderTriggerCondition::Below,
+        trigger_price: 0,
+        post_only: false,
+        ..resting_limit_order(1, PositionDirection::Short, 0)
+    };
+
+    assert_eq!(
+        dlob.insert_order_validated(order, Pubkey::new_unique(), 0),
+        Err(ErrorCode::InvalidOrderTrigger)
+    );
+}
+
+#[test]
+fn insert_order_validated_rejects_implausible_market_index() {
+    let mut dlob = DLOB::new().unwrap();
+    let order = Order {
+        market_index: MAX_PLAUSIBLE_MARKET_INDEX + 1,
+        ..resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+
+    assert_eq!(
+        dlob.insert_order_validated(order, Pubkey::new_unique(), 0),
+        Err(ErrorCode::InvalidOrder)
+    );
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn insert_order_emits_trace_event() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    struct CountingSubscriber {
+        events: Arc<AtomicUsize>,
+    }
+
+    impl Subscriber for CountingSubscriber {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _span: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            if event.metadata().target().starts_with("drift_sdk") {
+                self.events.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    let events = Arc::new(AtomicUsize::new(0));
+    let subscriber = CountingSubscriber {
+        events: events.clone(),
+    };
+
+    tracing::subscriber::with_default(subscriber, || {
+        let mut dlob = DLOB::new().unwrap();
+        let order = resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+        dlob.insert_order(order, Pubkey::new_unique(), 0).unwrap();
+    });
+
+    assert_eq!(events.load(Ordering::SeqCst), 1);
+}
+
+fn insert_two_sided_book(dlob: &mut DLOB, user: Pubkey) {
+    let bid = Order {
+        base_asset_amount: 3 * BASE_PRECISION_U64,
+        ..resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64)
+    };
+    let ask = resting_limit_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    dlob.insert_order(bid, user, 0).unwrap();
+    dlob.insert_order(ask, user, 0).unwrap();
+}
+
+#[test]
+fn get_mid_from_book() {
+    let mut dlob = DLOB::new().unwrap();
+    insert_two_sided_book(&mut dlob, Pubkey::new_unique());
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    assert_eq!(
+        dlob.get_mid(0, MarketType::Perp, MidSource::Book, &oracle_price_data, 0),
+        Some(100 * PRICE_PRECISION_U64 as i128)
+    );
+    assert_eq!(
+        dlob.get_mid(1, MarketType::Perp, MidSource::Book, &oracle_price_data, 0),
+        None
+    );
+}
+
+#[test]
+fn get_mid_from_oracle() {
+    let mut dlob = DLOB::new().unwrap();
+    insert_two_sided_book(&mut dlob, Pubkey::new_unique());
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    assert_eq!(
+        dlob.get_mid(
+            0,
+            MarketType::Perp,
+            MidSource::Oracle,
+            &oracle_price_data,
+            0
+        ),
+        Some(oracle_price_data.price as i128)
+    );
+    // the oracle mid doesn't need any liquidity
+    assert_eq!(
+        dlob.get_mid(
+            1,
+            MarketType::Perp,
+            MidSource::Oracle,
+            &oracle_price_data,
+            0
+        ),
+        Some(oracle_price_data.price as i128)
+    );
+}
+
+#[test]
+fn get_mid_weighted_by_size() {
+    let mut dlob = DLOB::new().unwrap();
+    insert_two_sided_book(&mut dlob, Pubkey::new_unique());
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    // 3 base bid vs 1 base ask pulls the mid towards the ask: (99 * 1 + 101 * 3) / 4
+    assert_eq!(
+        dlob.get_mid(
+            0,
+            MarketType::Perp,
+            MidSource::Weighted,
+            &oracle_price_data,
+            0
+        ),
+        Some(100_500_000)
+    );
+}
+
+#[test]
+fn decreasing_slot_is_clamped_to_latest() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    // not post only, so it only rests once its 10 slot auction is over
+    let taking_limit = |order_id| Order {
+        post_only: false,
+        auction_duration: 10,
+        ..resting_limit_order(order_id, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+
+    dlob.insert_order(taking_limit(1), user, 20).unwrap();
+    // a late event from before the auction ended must not be filed as taking
+    dlob.insert_order(taking_limit(2), user, 5).unwrap();
+
+    assert_eq!(dlob.latest_slot, 20);
+
+    let resting_bids = dlob
+        .get_side_list(0, MarketType::Perp, DLOBNodeType::RestingLimit, Side::Bid)
+        .unwrap();
+    assert_eq!(resting_bids.iter().count(), 2);
+    assert!(dlob
+        .get_side_list(0, MarketType::Perp, DLOBNodeType::TakingLimit, Side::Bid)
+        .map_or(true, |list| list.iter().count() == 0));
+}
+
+#[test]
+fn queue_position_behind_two_orders() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let maker = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let better = resting_limit_order(1, PositionDirection::Long, 101 * PRICE_PRECISION_U64);
+    let same_price = Order {
+        base_asset_amount: 2 * BASE_PRECISION_U64,
+        ..resting_limit_order(2, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+    let ours = Order {
+        slot: 1,
+        ..resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+    let worse = resting_limit_order(3, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+
+    dlob.insert_order(better, maker, 0).unwrap();
+    dlob.insert_order(same_price, maker, 0).unwrap();
+    dlob.insert_order(ours, user, 0).unwrap();
+    dlob.insert_order(worse, maker, 0).unwrap();
+
+    assert_eq!(
+        dlob.queue_position(1, user, 0, MarketType::Perp, 0, &oracle_price_data),
+        Some((2, 3 * BASE_PRECISION_U64))
+    );
+    assert_eq!(
+        dlob.queue_position(9, user, 0, MarketType::Perp, 0, &oracle_price_data),
+        None
+    );
+}
+
+#[test]
+fn labeled_lists_match_inserted_orders() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    let perp_bid = resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+    let spot_ask = Order {
+        market_type: drift::state::user::MarketType::Spot,
+        market_index: 1,
+        ..resting_limit_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64)
+    };
+    dlob.insert_order(perp_bid, user, 0).unwrap();
+    dlob.insert_order(spot_ask, user, 0).unwrap();
+
+    let mut labels: Vec<(MarketId, DLOBNodeType, OrderSubType, Vec<u32>)> = dlob
+        .iter_labeled_lists()
+        .map(|(market_id, node_type, sub_type, node_list)| {
+            let order_ids = node_list
+                .iter()
+                .filter_map(|node| node.order().map(|order| order.order_id))
+                .collect::<Vec<_>>();
+            (market_id, node_type, sub_type, order_ids)
+        })
+        .filter(|(_, _, _, order_ids)| !order_ids.is_empty())
+        .collect();
+    labels.sort_by_key(|(_, _, _, order_ids)| order_ids[0]);
+
+    assert_eq!(
+        labels,
+        vec![
+            (
+                MarketId::new(0, MarketType::Perp),
+                DLOBNodeType::RestingLimit,
+                OrderSubType::Side(Side::Bid),
+                vec![1]
+            ),
+            (
+                MarketId::new(1, MarketType::Spot),
+                DLOBNodeType::RestingLimit,
+                OrderSubType::Side(Side::Ask),
+                vec![2]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn trigger_market_order_would_fill_against_book_or_fallback() {
+    let mut dlob = DLOB::new().unwrap();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let stop_loss = Order {
+        order_type: OrderType::TriggerMarket,
+        trigger_condition: OrderTriggerCondition::Above,
+        trigger_price: 105 * PRICE_PRECISION_U64,
+        post_only: false,
+        ..resting_limit_order(1, PositionDirection::Long, 0)
+    };
+
+    assert!(!dlob.trigger_would_fill(&stop_loss, 0, &oracle_price_data, None, None));
+    assert!(dlob.trigger_would_fill(
+        &stop_loss,
+        0,
+        &oracle_price_data,
+        None,
+        Some(106 * PRICE_PRECISION_U64 as i128)
+    ));
+
+    let ask = resting_limit_order(2, PositionDirection::Short, 106 * PRICE_PRECISION_U64);
+    dlob.insert_order(ask, Pubkey::new_unique(), 0).unwrap();
+    assert!(dlob.trigger_would_fill(&stop_loss, 0, &oracle_price_data, None, None));
+
+    // a trigger limit below the best ask would rest instead
+    let stop_limit = Order {
+        order_type: OrderType::TriggerLimit,
+        price: 104 * PRICE_PRECISION_U64,
+        ..stop_loss
+    };
+    assert!(!dlob.trigger_would_fill(&stop_limit, 0, &oracle_price_data, None, None));
+}
+
+#[test]
+fn order_signatures_match_inserted_orders() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    let bid = resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+    let ask = resting_limit_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    let other_market = Order {
+        market_index: 1,
+        ..resting_limit_order(3, PositionDirection::Long, 99 * PRICE_PRECISION_U64)
+    };
+    for order in [bid, ask, other_market] {
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    let signatures: HashSet<String> = dlob
+        .order_signatures(0, MarketType::Perp)
+        .into_iter()
+        .collect();
+    let expected: HashSet<String> = [1, 2]
+        .into_iter()
+        .map(|order_id| get_order_signature(order_id, &user))
+        .collect();
+    assert_eq!(signatures, expected);
+}
+
+#[test]
+fn side_direction_round_trip() {
+    assert_eq!(Side::from_direction(PositionDirection::Long), Side::Bid);
+    assert_eq!(Side::from_direction(PositionDirection::Short), Side::Ask);
+
+    for side in [Side::Bid, Side::Ask] {
+        assert_eq!(Side::from_direction(side.to_direction()), side);
+    }
+    for direction in [PositionDirection::Long, PositionDirection::Short] {
+        assert_eq!(Side::from_direction(direction).to_direction(), direction);
+    }
+}
+
+#[test]
+fn orders_in_price_range_on_five_level_side() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    for (order_id, price) in [(1, 101), (2, 102), (3, 103), (4, 104), (5, 105)] {
+        let order = resting_limit_order(
+            order_id,
+            PositionDirection::Short,
+            price * PRICE_PRECISION_U64,
+        );
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    let order_ids: Vec<u32> = dlob
+        .get_orders_in_price_range(
+            0,
+            MarketType::Perp,
+            Side::Ask,
+            102 * PRICE_PRECISION_U64 as i128..=104 * PRICE_PRECISION_U64 as i128,
+            0,
+            &oracle_price_data,
+        )
+        .iter()
+        .map(|node| node.order().unwrap().order_id)
+        .collect();
+
+    assert_eq!(order_ids, vec![2, 3, 4]);
+}
+
+#[test]
+fn custom_node_factory_builds_wrapped_nodes() {
+    use std::sync::Arc;
+
+    let factory = Arc::new(CountingNodeFactory::default());
+    let mut dlob = DLOB::with_node_factory(factory.clone()).unwrap();
+    let order = resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+    dlob.insert_order(order, Pubkey::new_unique(), 0).unwrap();
+
+    assert_eq!(factory.created(), 1);
+
+    let node = dlob
+        .get_side_list(0, MarketType::Perp, DLOBNodeType::RestingLimit, Side::Bid)
+        .and_then(|list| list.iter().next())
+        .unwrap();
+    assert!(format!("{:?}", node).starts_with("CountingNode"));
+    assert_eq!(node.order().unwrap().order_id, 1);
+}
+
+#[test]
+fn total_quote_liquidity_sums_notional() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let bid_top = resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+    let bid_deep = Order {
+        base_asset_amount: 2 * BASE_PRECISION_U64,
+        base_asset_amount_filled: BASE_PRECISION_U64 / 2,
+        ..resting_limit_order(2, PositionDirection::Long, 98 * PRICE_PRECISION_U64)
+    };
+    dlob.insert_order(bid_top, user, 0).unwrap();
+    dlob.insert_order(bid_deep, user, 0).unwrap();
+
+    // 1 * 100 + 1.5 * 98
+    assert_eq!(
+        dlob.total_quote_liquidity(0, MarketType::Perp, Side::Bid, 0, &oracle_price_data),
+        247 * QUOTE_PRECISION
+    );
+    assert_eq!(
+        dlob.total_quote_liquidity(0, MarketType::Perp, Side::Ask, 0, &oracle_price_data),
+        0
+    );
+}
+
+#[test]
+fn clear_market_leaves_other_markets_intact() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    let perp_bid = resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+    let spot_bid = Order {
+        market_type: drift::state::user::MarketType::Spot,
+        market_index: 1,
+        ..resting_limit_order(2, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+    dlob.insert_order(perp_bid, user, 0).unwrap();
+    dlob.insert_order(spot_bid, user, 0).unwrap();
+
+    dlob.clear_market(0, MarketType::Perp);
+
+    assert!(dlob.order_signatures(0, MarketType::Perp).is_empty());
+    assert!(!dlob.open_orders[&MarketType::Perp].contains(&get_order_signature(1, &user)));
+
+    assert_eq!(
+        dlob.order_signatures(1, MarketType::Spot),
+        vec![get_order_signature(2, &user)]
+    );
+    assert!(dlob.open_orders[&MarketType::Spot].contains(&get_order_signature(2, &user)));
+}
+
+#[test]
+fn route_fills_splits_maker_and_vamm_fills() {
+    let mut dlob = DLOB::new().unwrap();
+    let maker = Pubkey::new_unique();
+    let taker = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let ask = resting_limit_order(1, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    dlob.insert_order(ask, maker, 0).unwrap();
+
+    // still in its auction, so it sits in the taking limit list
+    let taking_bid = Order {
+        post_only: false,
+        auction_duration: 10,
+        ..resting_limit_order(1, PositionDirection::Long, 102 * PRICE_PRECISION_U64)
+    };
+    let market_ask = Order {
+        order_type: OrderType::Market,
+        post_only: false,
+        ..resting_limit_order(2, PositionDirection::Short, 0)
+    };
+    dlob.insert_order(taking_bid, taker, 0).unwrap();
+    dlob.insert_order(market_ask, taker, 0).unwrap();
+
+    let fill_route = dlob.route_fills(
+        0,
+        MarketType::Perp,
+        0,
+        &oracle_price_data,
+        Some(99 * PRICE_PRECISION_U64 as i128),
+        Some(103 * PRICE_PRECISION_U64 as i128),
+    );
+
+    assert_eq!(fill_route.against_makers.len(), 1);
+    let (taker_node, maker_node) = &fill_route.against_makers[0];
+    assert_eq!(taker_node.order().unwrap().order_id, 1);
+    assert_eq!(taker_node.user_account(), Some(&taker));
+    assert_eq!(maker_node.user_account(), Some(&maker));
+
+    assert_eq!(fill_route.against_vamm.len(), 1);
+    assert_eq!(fill_route.against_vamm[0].order().unwrap().order_id, 2);
+}
+
+#[test]
+fn order_builder_builds_resting_limit_bid() {
+    let order = OrderBuilder::new()
+        .order_id(7)
+        .market(2, MarketType::Spot)
+        .long()
+        .price(100 * PRICE_PRECISION_U64)
+        .base(3 * BASE_PRECISION_U64)
+        .post_only(true)
+        .build();
+
+    assert_eq!(order.order_id, 7);
+    assert_eq!(order.market_index, 2);
+    assert_eq!(order.market_type, drift::state::user::MarketType::Spot);
+    assert_eq!(order.status, OrderStatus::Open);
+    assert_eq!(order.base_asset_amount, 3 * BASE_PRECISION_U64);
+    assert_eq!(determine_node_type(&order, 0), DLOBNodeType::RestingLimit);
+    assert_eq!(
+        determine_sub_type(&order, false),
+        OrderSubType::Side(Side::Bid)
+    );
+}
+
+#[test]
+fn can_fill_with_fallback_when_book_is_thin() {
+    let mut dlob = DLOB::new().unwrap();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let ask = resting_limit_order(1, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    dlob.insert_order(ask, Pubkey::new_unique(), 0).unwrap();
+
+    let quote = |price: u64, base_asset_amount| FallbackQuote {
+        price: price as i128,
+        base_asset_amount,
+    };
+    let can_fill = |base_amount, fallback_bid, fallback_ask| {
+        dlob.can_fill(
+            0,
+            MarketType::Perp,
+            PositionDirection::Long,
+            base_amount,
+            0,
+            &oracle_price_data,
+            fallback_bid,
+            fallback_ask,
+        )
+    };
+
+    assert!(can_fill(BASE_PRECISION_U64, None, None));
+    assert!(!can_fill(2 * BASE_PRECISION_U64, None, None));
+
+    // the vAMM only covers the base it quotes, on top of the book
+    let vamm_ask = Some(quote(102 * PRICE_PRECISION_U64, BASE_PRECISION_U64));
+    assert!(can_fill(2 * BASE_PRECISION_U64, None, vamm_ask));
+    assert!(!can_fill(3 * BASE_PRECISION_U64, None, vamm_ask));
+    assert!(can_fill(
+        3 * BASE_PRECISION_U64,
+        None,
+        Some(quote(102 * PRICE_PRECISION_U64, 2 * BASE_PRECISION_U64))
+    ));
+
+    // a zero price or crossed quotes add nothing
+    assert!(!can_fill(
+        2 * BASE_PRECISION_U64,
+        None,
+        Some(quote(0, BASE_PRECISION_U64))
+    ));
+    assert!(!can_fill(
+        2 * BASE_PRECISION_U64,
+        Some(quote(103 * PRICE_PRECISION_U64, BASE_PRECISION_U64)),
+        vamm_ask
+    ));
+    assert!(can_fill(
+        2 * BASE_PRECISION_U64,
+        Some(quote(100 * PRICE_PRECISION_U64, BASE_PRECISION_U64)),
+        vamm_ask
+    ));
+}
+
+#[test]
+fn expire_orders_pops_passed_buckets_in_order() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    for (order_id, max_ts) in [(1, 30), (2, 10), (3, 20), (4, 0)] {
+        let order = OrderBuilder::new()
+            .order_id(order_id)
+            .price(100 * PRICE_PRECISION_U64)
+            .post_only(true)
+            .max_ts(max_ts)
+            .build();
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    let expired_ids = |expired: Vec<crate::dlob_orders::DLOBOrder>| {
+        expired
+            .iter()
+            .map(|dlob_order| dlob_order.order.order_id)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        expired_ids(dlob.expire_orders(10).unwrap()),
+        Vec::<u32>::new()
+    );
+    assert_eq!(expired_ids(dlob.expire_orders(15).unwrap()), vec![2]);
+    assert_eq!(expired_ids(dlob.expire_orders(31).unwrap()), vec![3, 1]);
+
+    // orders without a max_ts never expire
+    let remaining: Vec<u32> = dlob
+        .snapshot_market(0, MarketType::Perp)
+        .orders
+        .iter()
+        .map(|dlob_order| dlob_order.order.order_id)
+        .collect();
+    assert_eq!(remaining, vec![4]);
+}
+
+#[test]
+fn frozen_dlob_l2_matches_live_book() {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    for (order_id, direction, price) in [
+        (1, PositionDirection::Long, 99),
+        (2, PositionDirection::Long, 99),
+        (3, PositionDirection::Long, 98),
+        (4, PositionDirection::Short, 101),
+    ] {
+        let order = resting_limit_order(order_id, direction, price * PRICE_PRECISION_U64);
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    let market_id = MarketId::new(0, MarketType::Perp);
+    let oracles = HashMap::from([(market_id, oracle_price_data)]);
+    let frozen = Arc::new(dlob.freeze(0, &oracles));
+    assert_send_sync(&frozen);
+
+    let live = dlob.get_l2(
+        0,
+        MarketType::Perp,
+        0,
+        &oracle_price_data,
+        L2Options::default(),
+    );
+    assert_eq!(frozen.get_l2(&market_id), Some(&live));
+    assert_eq!(live.bids.len(), 2);
+    assert_eq!(live.bids[0].num_orders, 2);
+    assert_eq!(
+        frozen.best_bid(&market_id),
+        Some(99 * PRICE_PRECISION_U64 as i128)
+    );
+    assert_eq!(
+        frozen.best_ask(&market_id),
+        Some(101 * PRICE_PRECISION_U64 as i128)
+    );
+    assert_eq!(frozen.slot(), 0);
+}
+
+#[test]
+fn twap_price_source_shifts_floating_orders() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let fixed_bid = resting_limit_order(1, PositionDirection::Long, 990_000);
+    let floating_bid = OrderBuilder::new()
+        .order_id(2)
+        .oracle_price_offset(-5_000)
+        .post_only(true)
+        .build();
+    dlob.insert_order(fixed_bid, user, 0).unwrap();
+    dlob.insert_order(floating_bid, user, 0).unwrap();
+
+    let spot = dlob.get_l2(
+        0,
+        MarketType::Perp,
+        0,
+        &oracle_price_data,
+        L2Options::default(),
+    );
+    assert_eq!(spot.best_bid(), Some(995_000));
+
+    let twap = dlob.get_l2_with_price_source(
+        0,
+        MarketType::Perp,
+        0,
+        &oracle_price_data,
+        L2Options::default(),
+        OraclePriceSource::Twap(980_000),
+    );
+    assert_eq!(twap.best_bid(), Some(990_000));
+    assert_eq!(twap.bids[1].price, 975_000);
+}
+
+#[test]
+fn user_order_count_spans_markets() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let other = Pubkey::new_unique();
+
+    let perp = OrderBuilder::new()
+        .order_id(1)
+        .price(PRICE_PRECISION_U64)
+        .build();
+    let spot = OrderBuilder::new()
+        .order_id(2)
+        .market(1, MarketType::Spot)
+        .price(PRICE_PRECISION_U64)
+        .build();
+    dlob.insert_order(perp, user, 0).unwrap();
+    dlob.insert_order(spot, user, 0).unwrap();
+    dlob.insert_order(perp, other, 0).unwrap();
+
+    assert_eq!(dlob.user_order_count(&user), 2);
+    assert_eq!(dlob.user_order_count(&other), 1);
+    assert_eq!(dlob.user_order_count(&Pubkey::new_unique()), 0);
+}
+
+#[test]
+fn open_order_signatures_iterate_in_stable_order() {
+    let users: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+    let orders = [
+        OrderBuilder::new()
+            .order_id(3)
+            .price(PRICE_PRECISION_U64)
+            .build(),
+        OrderBuilder::new()
+            .order_id(1)
+            .market(0, MarketType::Spot)
+            .price(PRICE_PRECISION_U64)
+            .build(),
+        OrderBuilder::new()
+            .order_id(2)
+            .price(PRICE_PRECISION_U64)
+            .build(),
+    ];
+
+    let dump = |reverse: bool| {
+        let mut dlob = DLOB::new().unwrap();
+        let mut inserts: Vec<(Order, Pubkey)> = users
+            .iter()
+            .flat_map(|user| orders.iter().map(move |order| (*order, *user)))
+            .collect();
+        if reverse {
+            inserts.reverse();
+        }
+        for (order, user) in inserts {
+            dlob.insert_order(order, user, 0).unwrap();
+        }
+        dlob.open_order_signatures()
+            .map(|(market_type, order_signature)| (market_type, order_signature.to_string()))
+            .collect::<Vec<_>>()
+    };
+
+    let forward = dump(false);
+    assert_eq!(forward, dump(true));
+    assert_eq!(forward.len(), 12);
+    assert!(forward[..8]
+        .iter()
+        .all(|(market_type, _)| *market_type == MarketType::Perp));
+    assert!(forward[..8].windows(2).all(|pair| pair[0].1 < pair[1].1));
+}
+
+#[test]
+fn reduce_order_shrinks_l2_level() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let bid = OrderBuilder::new()
+        .order_id(1)
+        .price(PRICE_PRECISION_U64)
+        .base(4 * BASE_PRECISION_U64)
+        .filled(BASE_PRECISION_U64)
+        .post_only(true)
+        .build();
+    dlob.insert_order(bid, user, 0).unwrap();
+
+    let l2 = dlob.get_l2(
+        0,
+        MarketType::Perp,
+        0,
+        &oracle_price_data,
+        L2Options {
+            depth: 10,
+            ..L2Options::default()
+        },
+    );
+    assert_eq!(l2.bids[0].size, 3 * BASE_PRECISION_U64);
+
+    dlob.reduce_order(bid, user, 2 * BASE_PRECISION_U64, 0)
+        .unwrap();
+
+    let l2 = dlob.get_l2(
+        0,
+        MarketType::Perp,
+        0,
+        &oracle_price_data,
+        L2Options {
+            depth: 10,
+            ..L2Options::default()
+        },
+    );
+    assert_eq!(l2.bids.len(), 1);
+    assert_eq!(l2.bids[0].size, BASE_PRECISION_U64);
+    assert_eq!(l2.bids[0].num_orders, 1);
+}
+
+#[test]
+fn price_fallback_controls_missing_oracle_reads() {
+    let mut dlob = DLOB::new().unwrap();
+    let cached = OraclePriceData {
+        price: 42 * PRICE_PRECISION_U64 as i64,
+        ..OraclePriceData::default_usd()
+    };
+    dlob.update_oracle_price(0, MarketType::Perp, cached);
+
+    dlob.set_price_fallback(PriceFallback::RejectQuery);
+    assert_eq!(
+        dlob.resolve_oracle_price(0, MarketType::Perp, None)
+            .unwrap_err(),
+        ErrorCode::OracleNotFound
+    );
+
+    dlob.set_price_fallback(PriceFallback::LastKnown);
+    assert_eq!(
+        dlob.resolve_oracle_price(0, MarketType::Perp, None)
+            .unwrap()
+            .price,
+        cached.price
+    );
+    assert_eq!(
+        dlob.resolve_oracle_price(0, MarketType::Spot, None)
+            .unwrap_err(),
+        ErrorCode::OracleNotFound
+    );
+
+    let fresh = OraclePriceData::default_usd();
+    assert_eq!(
+        dlob.resolve_oracle_price(0, MarketType::Perp, Some(&fresh))
+            .unwrap()
+            .price,
+        fresh.price
+    );
+}
+
+#[test]
+fn trigger_distances_orders_by_proximity() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let trigger_order = |order_id: u32, trigger_price: u64, condition| {
+        OrderBuilder::new()
+            .order_id(order_id)
+            .order_type(OrderType::TriggerMarket)
+            .price(0)
+            .trigger(trigger_price, condition)
+            .build()
+    };
+
+    // oracle is $1: fires 10c higher, already below its $1.05 trigger, fires 2c lower
+    let far_above = trigger_order(
+        1,
+        110 * PRICE_PRECISION_U64 / 100,
+        OrderTriggerCondition::Above,
+    );
+    let fired_below = trigger_order(
+        2,
+        105 * PRICE_PRECISION_U64 / 100,
+        OrderTriggerCondition::Below,
+    );
+    let near_below = trigger_order(
+        3,
+        98 * PRICE_PRECISION_U64 / 100,
+        OrderTriggerCondition::Below,
+    );
+    for order in [far_above, fired_below, near_below] {
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    let distances = dlob.trigger_distances(0, MarketType::Perp, &oracle_price_data);
+    let by_id: Vec<(u32, i128)> = distances
+        .iter()
+        .map(|(order, distance)| (order.order_id, *distance))
+        .collect();
+    assert_eq!(by_id, vec![(2, -50_000), (3, 20_000), (1, 100_000)]);
+}
+
+#[cfg(feature = "analytics")]
+#[test]
+fn realized_spread_tracks_mid_move_since_placement() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+    insert_two_sided_book(&mut dlob, user);
+
+    let bid = resting_limit_order(3, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+    assert_eq!(
+        dlob.record_placement_mid(&bid, &user, &oracle_price_data, 0),
+        Some(100 * PRICE_PRECISION_U64 as i128)
+    );
+    dlob.insert_order(bid, user, 0).unwrap();
+
+    // a tighter ask pulls the mid down to 99.5, against the bid
+    let ask = resting_limit_order(4, PositionDirection::Short, 100 * PRICE_PRECISION_U64);
+    dlob.insert_order(ask, user, 1).unwrap();
+
+    let order_signature = get_order_signature(bid.order_id, &user);
+    assert_eq!(
+        dlob.realized_spread(&order_signature, &oracle_price_data, 1),
+        Some(-(PRICE_PRECISION_U64 as i128) / 2)
+    );
+    assert_eq!(
+        dlob.realized_spread(&get_order_signature(4, &user), &oracle_price_data, 1),
+        None
+    );
+}
+
+#[test]
+fn remove_signatures_removes_batch_across_markets() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    let orders = [
+        resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64),
+        resting_limit_order(2, PositionDirection::Long, 98 * PRICE_PRECISION_U64),
+        resting_limit_order(3, PositionDirection::Short, 101 * PRICE_PRECISION_U64),
+        OrderBuilder::new()
+            .order_id(4)
+            .market(0, MarketType::Spot)
+            .price(PRICE_PRECISION_U64)
+            .post_only(true)
+            .build(),
+    ];
+    for order in orders {
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    let canceled: HashSet<String> = [1, 3, 4]
+        .into_iter()
+        .map(|order_id| get_order_signature(order_id, &user))
+        .collect();
+    assert_eq!(dlob.remove_signatures(&canceled, 0), 3);
+
+    assert_eq!(
+        dlob.order_signatures(0, MarketType::Perp),
+        vec![get_order_signature(2, &user)]
+    );
+    assert!(dlob.order_signatures(0, MarketType::Spot).is_empty());
+    assert_eq!(dlob.user_order_count(&user), 1);
+    dlob.validate().unwrap();
+
+    assert_eq!(dlob.remove_signatures(&canceled, 0), 0);
+}
+
+#[test]
+fn orders_at_price_returns_every_order_at_level() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    for order_id in 1..=3 {
+        let bid = resting_limit_order(order_id, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+        dlob.insert_order(bid, user, 0).unwrap();
+    }
+    let better_bid = resting_limit_order(4, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+    dlob.insert_order(better_bid, user, 0).unwrap();
+
+    let nodes = dlob.orders_at_price(
+        0,
+        MarketType::Perp,
+        Side::Bid,
+        99 * PRICE_PRECISION_U64 as i128,
+        0,
+        &oracle_price_data,
+    );
+    let order_ids: Vec<u32> = nodes
+        .iter()
+        .map(|node| node.order().unwrap().order_id)
+        .collect();
+    assert_eq!(order_ids, vec![1, 2, 3]);
+}
+
+#[test]
+fn get_l2_min_size_hides_dust() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let bid = resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+    let dust_bid = Order {
+        base_asset_amount: BASE_PRECISION_U64 / 1_000,
+        ..resting_limit_order(2, PositionDirection::Long, 99 * PRICE_PRECISION_U64)
+    };
+    let dust_only_bid = Order {
+        base_asset_amount: BASE_PRECISION_U64 / 1_000,
+        ..resting_limit_order(3, PositionDirection::Long, 98 * PRICE_PRECISION_U64)
+    };
+    for order in [bid, dust_bid, dust_only_bid] {
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    let l2 = dlob.get_l2(
+        0,
+        MarketType::Perp,
+        0,
+        &oracle_price_data,
+        L2Options {
+            depth: 10,
+            ..L2Options::default()
+        },
+    );
+    assert_eq!(l2.bids.len(), 2);
+    assert_eq!(l2.bids[0].num_orders, 2);
+
+    let l2 = dlob.get_l2(
+        0,
+        MarketType::Perp,
+        0,
+        &oracle_price_data,
+        L2Options {
+            depth: 10,
+            min_size: BASE_PRECISION_U64 / 100,
+            ..L2Options::default()
+        },
+    );
+    assert_eq!(l2.bids.len(), 1);
+    assert_eq!(l2.bids[0].size, BASE_PRECISION_U64);
+    assert_eq!(l2.bids[0].num_orders, 1);
+}
+
+#[test]
+fn reconcile_open_orders_drops_stale_signatures() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    insert_two_sided_book(&mut dlob, user);
+
+    let perp_open_orders = dlob.open_orders.get_mut(&MarketType::Perp).unwrap();
+    perp_open_orders.insert(get_order_signature(7, &user));
+    perp_open_orders.insert(get_order_signature(8, &user));
+    assert_eq!(dlob.user_order_count(&user), 4);
+
+    assert_eq!(dlob.reconcile_open_orders(), 2);
+    assert_eq!(dlob.user_order_count(&user), 2);
+    assert_eq!(dlob.reconcile_open_orders(), 0);
+}
+
+#[test]
+fn from_user_map_counts_orders_per_market() {
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let mut orders = [Order::default(); 32];
+    orders[0] = resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+    orders[1] = resting_limit_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    orders[2] = OrderBuilder::new()
+        .order_id(3)
+        .market(0, MarketType::Spot)
+        .price(PRICE_PRECISION_U64)
+        .post_only(true)
+        .build();
+    orders[3] = OrderBuilder::new()
+        .order_id(4)
+        .status(OrderStatus::Init)
+        .price(PRICE_PRECISION_U64)
+        .build();
+    let mut user = User {
+        orders,
+        ..User::default()
+    };
+
+    let user_key = Pubkey::new_unique();
+    let owner = User::owner();
+    let mut lamports = 0;
+    let mut data = get_anchor_account_bytes(&mut user);
+    let account_info = create_account_info(&user_key, &mut lamports, &mut data, &owner);
+    let mut user_map = UserMap::empty();
+    user_map
+        .insert(user_key, AccountLoader::try_from(&account_info).unwrap())
+        .unwrap();
+
+    let dlob = DLOB::from_user_map(&user_map, 0).unwrap();
+    assert_eq!(dlob.order_signatures(0, MarketType::Perp).len(), 2);
+    assert_eq!(dlob.order_signatures(0, MarketType::Spot).len(), 1);
+    assert_eq!(dlob.user_order_count(&user_key), 3);
+    assert_eq!(
+        dlob.get_mid(0, MarketType::Perp, MidSource::Book, &oracle_price_data, 0),
+        Some(100 * PRICE_PRECISION_U64 as i128)
+    );
+}
+
+#[test]
+fn protected_only_mode_ignores_unprotected_makers() {
+    let mut dlob = DLOB::new().unwrap();
+    let protected_maker = Pubkey::new_unique();
+    let other_maker = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+    dlob.add_protected_maker(protected_maker);
+
+    let protected_bid = resting_limit_order(1, PositionDirection::Long, 98 * PRICE_PRECISION_U64);
+    let protected_ask = resting_limit_order(2, PositionDirection::Short, 102 * PRICE_PRECISION_U64);
+    let better_bid = resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+    dlob.insert_order(protected_bid, protected_maker, 0)
+        .unwrap();
+    dlob.insert_order(protected_ask, protected_maker, 0)
+        .unwrap();
+    dlob.insert_order(better_bid, other_maker, 0).unwrap();
+
+    assert_eq!(
+        dlob.get_effective_bid_ask(0, MarketType::Perp, 0, &oracle_price_data, MakerMode::All),
+        (
+            Some(99 * PRICE_PRECISION_U64 as i128),
+            Some(102 * PRICE_PRECISION_U64 as i128)
+        )
+    );
+    assert_eq!(
+        dlob.get_effective_bid_ask(
+            0,
+            MarketType::Perp,
+            0,
+            &oracle_price_data,
+            MakerMode::ProtectedOnly
+        ),
+        (
+            Some(98 * PRICE_PRECISION_U64 as i128),
+            Some(102 * PRICE_PRECISION_U64 as i128)
+        )
+    );
+}
+
+#[test]
+fn get_triggered_unfilled_returns_fired_orders_without_fills() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    let trigger_order = |order_id: u32, condition| {
+        OrderBuilder::new()
+            .order_id(order_id)
+            .order_type(OrderType::TriggerMarket)
+            .price(0)
+            .trigger(PRICE_PRECISION_U64, condition)
+            .build()
+    };
+
+    let triggered = trigger_order(1, OrderTriggerCondition::TriggeredAbove);
+    let untriggered = trigger_order(2, OrderTriggerCondition::Above);
+    let partially_filled = Order {
+        base_asset_amount_filled: BASE_PRECISION_U64 / 2,
+        ..trigger_order(3, OrderTriggerCondition::TriggeredBelow)
+    };
+    let plain_market = OrderBuilder::new()
+        .order_id(4)
+        .order_type(OrderType::Market)
+        .price(0)
+        .build();
+    for order in [triggered, untriggered, partially_filled, plain_market] {
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    assert_eq!(
+        dlob.get_triggered_unfilled(0, MarketType::Perp),
+        vec![triggered]
+    );
+    assert!(dlob.get_triggered_unfilled(1, MarketType::Perp).is_empty());
+}
+
+#[test]
+fn insert_rejects_oracle_offset_over_bound() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+    dlob.set_max_abs_oracle_offset(Some(PRICE_PRECISION_U64 as u32));
+
+    let floating_bid = |order_id: u32, oracle_price_offset: i32| {
+        OrderBuilder::new()
+            .order_id(order_id)
+            .oracle_price_offset(oracle_price_offset)
+            .post_only(true)
+            .build()
+    };
+
+    assert_eq!(
+        dlob.insert_order(
+            floating_bid(1, -1_000 * PRICE_PRECISION_U64 as i32),
+            user,
+            0
+        ),
+        Err(ErrorCode::InvalidOrderOracleOffset)
+    );
+    dlob.insert_order(floating_bid(2, -(PRICE_PRECISION_U64 as i32) / 2), user, 0)
+        .unwrap();
+
+    assert_eq!(dlob.user_order_count(&user), 1);
+    let (best_bid, _) =
+        dlob.get_effective_bid_ask(0, MarketType::Perp, 0, &oracle_price_data, MakerMode::All);
+    assert_eq!(best_bid, Some(500_000));
+}
+
+#[test]
+fn with_node_lists_borrows_every_list() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    insert_two_sided_book(&mut dlob, user);
+    let spot_bid = OrderBuilder::new()
+        .order_id(3)
+        .market(0, MarketType::Spot)
+        .price(PRICE_PRECISION_U64)
+        .post_only(true)
+        .build();
+    dlob.insert_order(spot_bid, user, 0).unwrap();
+
+    let node_count = dlob.with_node_lists(|node_lists| {
+        node_lists
+            .iter()
+            .map(|node_list| node_list.iter().count())
+            .sum::<usize>()
+    });
+    assert_eq!(node_count, 3);
+}
+
+#[test]
+fn init_from_user_map_skips_init_orders() {
+    let perp_bid = resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+    let init_bid = Order {
+        status: OrderStatus::Init,
+        ..resting_limit_order(2, PositionDirection::Long, 98 * PRICE_PRECISION_U64)
+    };
+    let spot_ask = OrderBuilder::new()
+        .order_id(3)
+        .market(1, MarketType::Spot)
+        .short()
+        .price(PRICE_PRECISION_U64)
+        .post_only(true)
+        .build();
+
+    let mut first_orders = [Order::default(); 32];
+    first_orders[0] = perp_bid;
+    first_orders[1] = init_bid;
+    let mut second_orders = [Order::default(); 32];
+    second_orders[0] = perp_bid;
+    second_orders[5] = spot_ask;
+    second_orders[6] = init_bid;
+
+    let mut first_user = User {
+        orders: first_orders,
+        ..User::default()
+    };
+    let mut second_user = User {
+        orders: second_orders,
+        ..User::default()
+    };
+
+    let owner = User::owner();
+    let first_key = Pubkey::new_unique();
+    let second_key = Pubkey::new_unique();
+    let (mut first_lamports, mut second_lamports) = (0, 0);
+    let mut first_data = get_anchor_account_bytes(&mut first_user);
+    let mut second_data = get_anchor_account_bytes(&mut second_user);
+    let first_info = create_account_info(&first_key, &mut first_lamports, &mut first_data, &owner);
+    let second_info =
+        create_account_info(&second_key, &mut second_lamports, &mut second_data, &owner);
+
+    let mut user_map = UserMap::empty();
+    user_map
+        .insert(first_key, AccountLoader::try_from(&first_info).unwrap())
+        .unwrap();
+    user_map
+        .insert(second_key, AccountLoader::try_from(&second_info).unwrap())
+        .unwrap();
+
+    let mut dlob = DLOB::new().unwrap();
+    assert!(dlob.init_from_user_map(&user_map, 0).unwrap());
+    assert!(!dlob.init_from_user_map(&user_map, 0).unwrap());
+
+    assert_eq!(dlob.open_orders[&MarketType::Perp].len(), 2);
+    assert_eq!(dlob.open_orders[&MarketType::Spot].len(), 1);
+    assert_eq!(dlob.user_order_count(&first_key), 1);
+    assert_eq!(dlob.user_order_count(&second_key), 2);
+}
+
+#[test]
+fn get_best_for_taker_picks_opposing_side() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+    insert_two_sided_book(&mut dlob, user);
+
+    let best_order_id = |taker_direction| {
+        dlob.get_best_for_taker(0, MarketType::Perp, taker_direction, 0, &oracle_price_data)
+            .map(|node| node.order().unwrap().order_id)
+    };
+    assert_eq!(best_order_id(PositionDirection::Long), Some(2));
+    assert_eq!(best_order_id(PositionDirection::Short), Some(1));
+
+    assert!(dlob
+        .get_best_for_taker(
+            1,
+            MarketType::Perp,
+            PositionDirection::Long,
+            0,
+            &oracle_price_data
+        )
+        .is_none());
+}
+
+#[test]
+fn get_best_bid_includes_matured_taking_orders() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+    insert_two_sided_book(&mut dlob, user);
+
+    let taking_bid = OrderBuilder::new()
+        .order_id(3)
+        .price(100 * PRICE_PRECISION_U64)
+        .auction(0, 0, 10)
+        .build();
+    dlob.insert_order(taking_bid, user, 0).unwrap();
+
+    assert_eq!(
+        dlob.get_best_bid(0, MarketType::Perp, 0, &oracle_price_data),
+        Some(99 * PRICE_PRECISION_U64 as i128)
+    );
+    assert_eq!(
+        dlob.get_best_bid(0, MarketType::Perp, 20, &oracle_price_data),
+        Some(100 * PRICE_PRECISION_U64 as i128)
+    );
+    assert_eq!(
+        dlob.get_best_ask(0, MarketType::Perp, 20, &oracle_price_data),
+        Some(101 * PRICE_PRECISION_U64 as i128)
+    );
+    assert_eq!(
+        dlob.get_best_ask(1, MarketType::Perp, 0, &oracle_price_data),
+        None
+    );
+}
+
+#[test]
+fn fill_recorder_receives_partial_fill() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let bid = Order {
+        base_asset_amount: 4 * BASE_PRECISION_U64,
+        ..resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64)
+    };
+    dlob.insert_order(bid, user, 0).unwrap();
+
+    let fills = Rc::new(RefCell::new(vec![]));
+    let recorded = fills.clone();
+    dlob.set_fill_recorder(Some(Box::new(
+        move |order_signature: &str, fill_base, fill_price, slot| {
+            recorded
+                .borrow_mut()
+                .push((order_signature.to_string(), fill_base, fill_price, slot));
+        },
+    )));
+
+    dlob.update_order(bid, user, 5, BASE_PRECISION_U64).unwrap();
+    // replaying the same cumulative fill is not a new fill
+    let partially_filled = Order {
+        base_asset_amount_filled: BASE_PRECISION_U64,
+        ..bid
+    };
+    dlob.update_order(partially_filled, user, 6, BASE_PRECISION_U64)
+        .unwrap();
+
+    assert_eq!(
+        *fills.borrow(),
+        vec![(
+            get_order_signature(1, &user),
+            BASE_PRECISION_U64,
+            99 * PRICE_PRECISION_U64 as i128,
+            5
+        )]
+    );
+}
+
+#[test]
+fn cumulative_l2_sizes_grow_from_best_level() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    for (order_id, price, base) in [(1, 99, 1), (2, 99, 2), (3, 98, 3), (4, 97, 4)] {
+        let bid = Order {
+            base_asset_amount: base * BASE_PRECISION_U64,
+            ..resting_limit_order(
+                order_id,
+                PositionDirection::Long,
+                price * PRICE_PRECISION_U64,
+            )
+        };
+        dlob.insert_order(bid, user, 0).unwrap();
+    }
+
+    let l2 = dlob.get_l2(
+        0,
+        MarketType::Perp,
+        0,
+        &oracle_price_data,
+        L2Options {
+            depth: 10,
+            cumulative: true,
+            ..L2Options::default()
+        },
+    );
+    let cumulative_sizes: Vec<u64> = l2
+        .bids
+        .iter()
+        .map(|level| level.cumulative_size / BASE_PRECISION_U64)
+        .collect();
+    assert_eq!(cumulative_sizes, vec![3, 6, 10]);
+    assert!(l2
+        .bids
+        .windows(2)
+        .all(|pair| pair[0].cumulative_size <= pair[1].cumulative_size));
+
+    let l2 = dlob.get_l2(
+        0,
+        MarketType::Perp,
+        0,
+        &oracle_price_data,
+        L2Options {
+            depth: 10,
+            ..L2Options::default()
+        },
+    );
+    assert!(l2.bids.iter().all(|level| level.cumulative_size == 0));
+}
+
+#[test]
+fn find_nodes_to_fill_prefers_better_injected_maker() {
+    let mut dlob = DLOB::new().unwrap();
+    let taker = Pubkey::new_unique();
+    let book_maker = Pubkey::new_unique();
+    let synthetic_maker = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let book_ask = resting_limit_order(1, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    dlob.insert_order(book_ask, book_maker, 0).unwrap();
+    let market_bid = OrderBuilder::new()
+        .order_id(2)
+        .order_type(OrderType::Market)
+        .price(0)
+        .build();
+    dlob.insert_order(market_bid, taker, 0).unwrap();
+
+    let nodes_to_fill = dlob.find_nodes_to_fill(0, MarketType::Perp, 0, &oracle_price_data);
+    assert_eq!(nodes_to_fill.len(), 1);
+    assert_eq!(
+        nodes_to_fill[0].maker_nodes[0].user_account(),
+        Some(&book_maker)
+    );
+
+    let synthetic_ask = create_node(
+        DLOBNodeType::RestingLimit,
+        resting_limit_order(3, PositionDirection::Short, 100 * PRICE_PRECISION_U64),
+        synthetic_maker,
+    );
+    let nodes_to_fill = dlob.find_nodes_to_fill_with_extra(
+        0,
+        MarketType::Perp,
+        0,
+        &oracle_price_data,
+        &[synthetic_ask],
+    );
+    assert_eq!(nodes_to_fill.len(), 1);
+    assert_eq!(nodes_to_fill[0].node.user_account(), Some(&taker));
+    let maker_accounts: Vec<_> = nodes_to_fill[0]
+        .maker_nodes
+        .iter()
+        .map(|maker| maker.user_account().copied())
+        .collect();
+    assert_eq!(maker_accounts, vec![Some(synthetic_maker)]);
+}
+
+#[test]
+fn tied_makers_order_fixed_before_floating_then_by_slot() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let floating_bid = OrderBuilder::new()
+        .order_id(1)
+        .oracle_price_offset(-10_000)
+        .post_only(true)
+        .slot(1)
+        .build();
+    let newer_fixed_bid = OrderBuilder::new()
+        .order_id(2)
+        .price(990_000)
+        .post_only(true)
+        .slot(9)
+        .build();
+    let older_fixed_bid = OrderBuilder::new()
+        .order_id(3)
+        .price(990_000)
+        .post_only(true)
+        .slot(5)
+        .build();
+    for order in [floating_bid, newer_fixed_bid, older_fixed_bid] {
+        dlob.insert_order(order, user, 10).unwrap();
+    }
+
+    let order_ids: Vec<u32> = dlob
+        .orders_at_price(
+            0,
+            MarketType::Perp,
+            Side::Bid,
+            990_000,
+            10,
+            &oracle_price_data,
+        )
+        .iter()
+        .map(|node| node.order().unwrap().order_id)
+        .collect();
+    assert_eq!(order_ids, vec![3, 2, 1]);
+}
+
+#[test]
+fn depth_weighted_mid_averages_weighted_sides() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let orders = [
+        (1, PositionDirection::Long, 99, 1),
+        (2, PositionDirection::Long, 98, 3),
+        (3, PositionDirection::Long, 90, 10),
+        (4, PositionDirection::Short, 101, 1),
+        (5, PositionDirection::Short, 102, 1),
+    ];
+    for (order_id, direction, price, base) in orders {
+        let order = Order {
+            base_asset_amount: base * BASE_PRECISION_U64,
+            ..resting_limit_order(order_id, direction, price * PRICE_PRECISION_U64)
+        };
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    // bids (99 * 1 + 98 * 3) / 4 = 98.25, asks (101 + 102) / 2 = 101.5, the 90 bid is past depth
+    assert_eq!(
+        dlob.depth_weighted_mid(0, MarketType::Perp, 2, &oracle_price_data, 0),
+        Some(99_875_000)
+    );
+    assert_eq!(
+        dlob.depth_weighted_mid(1, MarketType::Perp, 2, &oracle_price_data, 0),
+        None
+    );
+}
+
+#[test]
+fn get_l2_snapshot_buckets_and_caps_levels() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let orders = [
+        (1, PositionDirection::Long, 99, 1),
+        (2, PositionDirection::Long, 99, 2),
+        (3, PositionDirection::Long, 98, 1),
+        (4, PositionDirection::Long, 97, 1),
+        (5, PositionDirection::Short, 101, 4),
+    ];
+    for (order_id, direction, price, base) in orders {
+        let order = Order {
+            base_asset_amount: base * BASE_PRECISION_U64,
+            ..resting_limit_order(order_id, direction, price * PRICE_PRECISION_U64)
+        };
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    let l2 = dlob.get_l2_snapshot(0, MarketType::Perp, 0, &oracle_price_data, 2);
+    let level = |price: u64, base: u64, num_orders| L2Level {
+        price: (price * PRICE_PRECISION_U64) as i128,
+        size: base * BASE_PRECISION_U64,
+        num_orders,
+        cumulative_size: 0,
+    };
+    assert_eq!(l2.bids, vec![level(99, 3, 2), level(98, 1, 1)]);
+    assert_eq!(l2.asks, vec![level(101, 4, 1)]);
+}
+
+#[test]
+fn get_node_reflects_updates() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let order = Order {
+        base_asset_amount: 3 * BASE_PRECISION_U64,
+        ..resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64)
+    };
+    dlob.insert_order(order, user, 0).unwrap();
+
+    let node = dlob.get_node(1, user, 0, MarketType::Perp).unwrap();
+    assert_eq!(
+        node.order().unwrap().base_asset_amount,
+        3 * BASE_PRECISION_U64
+    );
+    assert!(dlob.get_node(1, user, 1, MarketType::Perp).is_none());
+    assert!(dlob.get_node(1, user, 0, MarketType::Spot).is_none());
+
+    dlob.reduce_order(order, user, 2 * BASE_PRECISION_U64, 0)
+        .unwrap();
+
+    let node = dlob.get_node(1, user, 0, MarketType::Perp).unwrap();
+    assert_eq!(
+        node.order().unwrap().base_asset_amount,
+        2 * BASE_PRECISION_U64
+    );
+}
+
+#[test]
+fn get_l3_snapshot_keeps_queue_order() {
+    let mut dlob = DLOB::new().unwrap();
+    let first_maker = Pubkey::new_unique();
+    let second_maker = Pubkey::new_unique();
+    let price = 99 * PRICE_PRECISION_U64;
+
+    dlob.insert_order(
+        resting_limit_order(7, PositionDirection::Long, price),
+        first_maker,
+        0,
+    )
+    .unwrap();
+    dlob.insert_order(
+        resting_limit_order(3, PositionDirection::Long, price),
+        second_maker,
+        0,
+    )
+    .unwrap();
+    let floating_ask = OrderBuilder::new()
+        .order_id(4)
+        .short()
+        .oracle_price_offset(PRICE_PRECISION_U64 as i32)
+        .post_only(true)
+        .build();
+    dlob.insert_order(floating_ask, first_maker, 0).unwrap();
+
+    let l3 = dlob.get_l3_snapshot(0, MarketType::Perp, 0);
+    let bid = |maker, order_id| L3Order {
+        price: price as i128,
+        size: BASE_PRECISION_U64,
+        maker,
+        order_id,
+    };
+    assert_eq!(l3.bids, vec![bid(first_maker, 7), bid(second_maker, 3)]);
+    // floating orders can't be priced until an oracle price is known
+    assert!(l3.asks.is_empty());
+
+    // a cached price is only used once the fallback allows it
+    dlob.update_oracle_price(0, MarketType::Perp, OraclePriceData::default_usd());
+    assert!(dlob.get_l3_snapshot(0, MarketType::Perp, 0).asks.is_empty());
+
+    dlob.set_price_fallback(PriceFallback::LastKnown);
+    let l3 = dlob.get_l3_snapshot(0, MarketType::Perp, 0);
+    assert_eq!(
+        l3.asks,
+        vec![L3Order {
+            price: 2 * PRICE_PRECISION_U64 as i128,
+            size: BASE_PRECISION_U64,
+            maker: first_maker,
+            order_id: 4,
+        }]
+    );
+}
+
+#[test]
+fn find_nodes_to_fill_caps_market_takers_to_price_band() {
+    let mut dlob = DLOB::new().unwrap();
+    let taker = Pubkey::new_unique();
+    let near_maker = Pubkey::new_unique();
+    let far_maker = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let near_ask =
+        resting_limit_order(1, PositionDirection::Short, 101 * PRICE_PRECISION_U64 / 100);
+    dlob.insert_order(near_ask, near_maker, 0).unwrap();
+    let far_ask = resting_limit_order(2, PositionDirection::Short, 2 * PRICE_PRECISION_U64);
+    dlob.insert_order(far_ask, far_maker, 0).unwrap();
+    let market_bid = OrderBuilder::new()
+        .order_id(3)
+        .order_type(OrderType::Market)
+        .base(2 * BASE_PRECISION_U64)
+        .build();
+    dlob.insert_order(market_bid, taker, 0).unwrap();
+
+    let maker_accounts = |dlob: &DLOB| {
+        let nodes_to_fill = dlob.find_nodes_to_fill(0, MarketType::Perp, 0, &oracle_price_data);
+        assert_eq!(nodes_to_fill.len(), 1);
+        nodes_to_fill[0]
+            .maker_nodes
+            .iter()
+            .map(|maker| *maker.user_account().unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(maker_accounts(&dlob), vec![near_maker, far_maker]);
+
+    // 10% band around a $1 oracle stops the taker at $1.10
+    dlob.set_market_price_band(Some(1_000));
+    assert_eq!(maker_accounts(&dlob), vec![near_maker]);
+}
+
+#[test]
+fn get_resting_limit_bids_chains_resting_then_floating() {
+    use std::sync::Arc;
+
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    dlob.insert_order(
+        resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64),
+        user,
+        0,
+    )
+    .unwrap();
+    let floating_bid = OrderBuilder::new()
+        .order_id(2)
+        .oracle_price_offset(-(PRICE_PRECISION_U64 as i32))
+        .post_only(true)
+        .build();
+    dlob.insert_order(floating_bid, user, 0).unwrap();
+    let taking_bid = OrderBuilder::new()
+        .order_id(3)
+        .price(100 * PRICE_PRECISION_U64)
+        .auction(0, 0, 10)
+        .build();
+    dlob.insert_order(taking_bid, user, 0).unwrap();
+    dlob.insert_order(
+        resting_limit_order(4, PositionDirection::Short, 101 * PRICE_PRECISION_U64),
+        user,
+        0,
+    )
+    .unwrap();
+
+    let order_ids = |nodes: Vec<Arc<dyn DLOBNode>>| -> Vec<u32> {
+        nodes
+            .iter()
+            .map(|node| node.order().unwrap().order_id)
+            .collect()
+    };
+
+    let bids = dlob
+        .get_resting_limit_bids(0, MarketType::Perp, 0)
+        .collect();
+    assert_eq!(order_ids(bids), vec![1, 2]);
+
+    // the taking bid's auction has ended, so it rests ahead of the worse priced bid
+    let bids = dlob
+        .get_resting_limit_bids(0, MarketType::Perp, 20)
+        .collect();
+    assert_eq!(order_ids(bids), vec![3, 1, 2]);
+
+    let asks = dlob
+        .get_resting_limit_asks(0, MarketType::Perp, 20)
+        .collect();
+    assert_eq!(order_ids(asks), vec![4]);
+}
+
+#[test]
+fn replay_records_applies_in_slot_order() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    let filled = Order {
+        base_asset_amount: 3 * BASE_PRECISION_U64,
+        ..resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64)
+    };
+    let cancelled = resting_limit_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    dlob.insert_order(filled, user, 0).unwrap();
+    dlob.insert_order(cancelled, user, 0).unwrap();
+
+    let fill = |cumulative_filled| {
+        order_action_record(
+            OrderAction::Fill,
+            user,
+            Order {
+                base_asset_amount_filled: cumulative_filled,
+                ..filled
+            },
+        )
+    };
+    let records = vec![
+        (fill(2 * BASE_PRECISION_U64), 5),
+        (order_action_record(OrderAction::Cancel, user, cancelled), 3),
+        (fill(BASE_PRECISION_U64), 2),
+    ];
+    dlob.replay_records(records).unwrap();
+
+    assert!(dlob.get_node(2, user, 0, MarketType::Perp).is_none());
+    let node = dlob.get_node(1, user, 0, MarketType::Perp).unwrap();
+    let order = node.order().unwrap();
+    assert_eq!(
+        order.base_asset_amount - order.base_asset_amount_filled,
+        BASE_PRECISION_U64
+    );
+}
+
+#[test]
+fn get_taking_bids_merges_market_and_taking_limit_in_slot_order() {
+    use std::sync::Arc;
+
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    let taking_bid = OrderBuilder::new()
+        .order_id(1)
+        .price(100 * PRICE_PRECISION_U64)
+        .auction(0, 0, 10)
+        .slot(1)
+        .build();
+    dlob.insert_order(taking_bid, user, 1).unwrap();
+    let market_bid = OrderBuilder::new()
+        .order_id(2)
+        .order_type(OrderType::Market)
+        .auction(0, 0, 10)
+        .slot(2)
+        .build();
+    dlob.insert_order(market_bid, user, 2).unwrap();
+    let market_ask = OrderBuilder::new()
+        .order_id(3)
+        .short()
+        .order_type(OrderType::Market)
+        .slot(2)
+        .build();
+    dlob.insert_order(market_ask, user, 2).unwrap();
+
+    let order_ids = |nodes: Vec<Arc<dyn DLOBNode>>| -> Vec<u32> {
+        nodes
+            .iter()
+            .map(|node| node.order().unwrap().order_id)
+            .collect()
+    };
+    assert_eq!(
+        order_ids(dlob.get_taking_bids(0, MarketType::Perp, 2).collect()),
+        vec![1, 2]
+    );
+    assert_eq!(
+        order_ids(dlob.get_taking_asks(0, MarketType::Perp, 2).collect()),
+        vec![3]
+    );
+
+    // once its auction ends the taking limit bid rests instead
+    assert_eq!(
+        order_ids(dlob.get_taking_bids(0, MarketType::Perp, 20).collect()),
+        vec![2]
+    );
+}
+
+#[test]
+fn round_trip_cost_on_symmetric_book() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    let orders = [
+        (1, PositionDirection::Long, 99),
+        (2, PositionDirection::Long, 98),
+        (3, PositionDirection::Short, 101),
+        (4, PositionDirection::Short, 102),
+    ];
+    for (order_id, direction, price) in orders {
+        dlob.insert_order(
+            resting_limit_order(order_id, direction, price * PRICE_PRECISION_U64),
+            user,
+            0,
+        )
+        .unwrap();
+    }
+
+    // buy 2 for 101 + 102, sell them back for 99 + 98
+    assert_eq!(
+        dlob.round_trip_cost(
+            0,
+            MarketType::Perp,
+            2 * BASE_PRECISION_U64,
+            0,
+            &oracle_price_data
+        ),
+        Some(6 * QUOTE_PRECISION as i128)
+    );
+    assert_eq!(
+        dlob.round_trip_cost(
+            0,
+            MarketType::Perp,
+            BASE_PRECISION_U64,
+            0,
+            &oracle_price_data
+        ),
+        Some(2 * QUOTE_PRECISION as i128)
+    );
+    assert_eq!(
+        dlob.round_trip_cost(
+            0,
+            MarketType::Perp,
+            3 * BASE_PRECISION_U64,
+            0,
+            &oracle_price_data
+        ),
+        None
+    );
+}
+
+#[test]
+fn find_crossing_nodes_pairs_crossing_orders_only() {
+    let mut dlob = DLOB::new().unwrap();
+    let taker = Pubkey::new_unique();
+    let maker = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    // crosses the 100 ask, and only needs what's left of it
+    let crossing_bid = OrderBuilder::new()
+        .order_id(1)
+        .price(100 * PRICE_PRECISION_U64)
+        .auction(
+            100 * PRICE_PRECISION_U64 as i64,
+            100 * PRICE_PRECISION_U64 as i64,
+            10,
+        )
+        .build();
+    dlob.insert_order(crossing_bid, taker, 0).unwrap();
+    let partially_filled_ask = Order {
+        base_asset_amount: 2 * BASE_PRECISION_U64,
+        base_asset_amount_filled: BASE_PRECISION_U64,
+        ..resting_limit_order(2, PositionDirection::Short, 100 * PRICE_PRECISION_U64)
+    };
+    dlob.insert_order(partially_filled_ask, maker, 0).unwrap();
+
+    // asks above the only resting bid, so never crosses
+    let non_crossing_ask = OrderBuilder::new()
+        .order_id(3)
+        .short()
+        .price(105 * PRICE_PRECISION_U64)
+        .auction(
+            105 * PRICE_PRECISION_U64 as i64,
+            105 * PRICE_PRECISION_U64 as i64,
+            10,
+        )
+        .build();
+    dlob.insert_order(non_crossing_ask, taker, 0).unwrap();
+    dlob.insert_order(
+        resting_limit_order(4, PositionDirection::Long, 95 * PRICE_PRECISION_U64),
+        maker,
+        0,
+    )
+    .unwrap();
+
+    let pairs: Vec<(u32, u32)> = dlob
+        .find_crossing_nodes(0, MarketType::Perp, 0, &oracle_price_data)
+        .iter()
+        .map(|(taker, maker)| {
+            (
+                taker.order().unwrap().order_id,
+                maker.order().unwrap().order_id,
+            )
+        })
+        .collect();
+    assert_eq!(pairs, vec![(1, 2)]);
+}
+
+/// Benchmark-style check: on a resting-only market the best bid is read off the list head,
+/// so it prices a single node however deep the book is.
+#[test]
+fn best_bid_fast_path_is_constant_time() {
+    use std::sync::Arc;
+
+    let oracle_price_data = OraclePriceData::default_usd();
+    for book_depth in [10_u32, 1_000] {
+        let factory = Arc::new(CountingNodeFactory::default());
+        let mut dlob = DLOB::with_node_factory(factory.clone()).unwrap();
+        let user = Pubkey::new_unique();
+        for order_id in 1..=book_depth {
+            let price = (order_id as u64) * PRICE_PRECISION_U64;
+            dlob.insert_order(
+                resting_limit_order(order_id, PositionDirection::Long, price),
+                user,
+                0,
+            )
+            .unwrap();
+        }
+
+        factory.reset();
+        let best_bid = dlob.get_best_bid(0, MarketType::Perp, 0, &oracle_price_data);
+
+        assert_eq!(
+            best_bid,
+            Some((book_depth as u64 * PRICE_PRECISION_U64) as i128)
+        );
+        assert_eq!(factory.get_price_calls(), 1);
+    }
+}
+
+#[test]
+fn best_price_on_floating_only_side_is_best_offset() {
+    use std::sync::Arc;
+
+    let factory = Arc::new(CountingNodeFactory::default());
+    let mut dlob = DLOB::with_node_factory(factory.clone()).unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData {
+        price: 100 * PRICE_PRECISION_U64 as i64,
+        ..OraclePriceData::default_usd()
+    };
+
+    let floating_order = |order_id: u32, direction: PositionDirection, offset: i32| {
+        OrderBuilder::new()
+            .order_id(order_id)
+            .direction(direction)
+            .oracle_price_offset(offset * PRICE_PRECISION_U64 as i32)
+            .post_only(true)
+            .slot(order_id as u64)
+            .build()
+    };
+    // the oldest order on each side has the worst offset
+    for (order_id, offset) in [(1, -3), (2, -1), (3, -2)] {
+        dlob.insert_order(
+            floating_order(order_id, PositionDirection::Long, offset),
+            user,
+            0,
+        )
+        .unwrap();
+    }
+    for (order_id, offset) in [(4, 3), (5, 1), (6, 2)] {
+        dlob.insert_order(
+            floating_order(order_id, PositionDirection::Short, offset),
+            user,
+            0,
+        )
+        .unwrap();
+    }
+
+    factory.reset();
+    assert_eq!(
+        dlob.get_best_bid(0, MarketType::Perp, 0, &oracle_price_data),
+        Some(99 * PRICE_PRECISION_U64 as i128)
+    );
+    assert_eq!(factory.get_price_calls(), 1);
+    assert_eq!(
+        dlob.get_best_ask(0, MarketType::Perp, 0, &oracle_price_data),
+        Some(101 * PRICE_PRECISION_U64 as i128)
+    );
+}
+
+#[test]
+fn to_csv_lists_levels_best_first() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+    insert_two_sided_book(&mut dlob, user);
+
+    let csv = dlob.to_csv(0, MarketType::Perp, &oracle_price_data, 0);
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("side,price,size,num_orders"));
+    assert_eq!(lines.next(), Some("bid,99000000,3000000000,1"));
+    assert!(csv.ends_with("ask,101000000,1000000000,1\n"));
+}
+
+#[test]
+fn vamm_quotes_count_towards_best_price_and_fills() {
+    use drift::math::constants::{AMM_RESERVE_PRECISION, PEG_PRECISION};
+    use drift::state::perp_market::AMM;
+
+    let mut dlob = DLOB::new().unwrap();
+    let maker = Pubkey::new_unique();
+    let taker = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    // asks at $1.01 while the vAMM quotes $0.999 / $1.001
+    let ask = resting_limit_order(1, PositionDirection::Short, 101 * PRICE_PRECISION_U64 / 100);
+    dlob.insert_order(ask, maker, 0).unwrap();
+    let bid = resting_limit_order(2, PositionDirection::Long, 99 * PRICE_PRECISION_U64 / 100);
+    dlob.insert_order(bid, maker, 0).unwrap();
+    let market_bid = OrderBuilder::new()
+        .order_id(3)
+        .order_type(OrderType::Market)
+        .build();
+    dlob.insert_order(market_bid, taker, 0).unwrap();
+
+    dlob.set_vamm(
+        0,
+        Some(AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: PEG_PRECISION,
+            long_spread: 1_000,
+            short_spread: 1_000,
+            ..AMM::default()
+        }),
+    );
+
+    assert_eq!(
+        dlob.get_best_ask(0, MarketType::Perp, 0, &oracle_price_data),
+        Some(1_001_000)
+    );
+    assert_eq!(
+        dlob.get_best_bid(0, MarketType::Perp, 0, &oracle_price_data),
+        Some(999_000)
+    );
+    assert!(dlob.get_vamm_node(0, MarketType::Spot, Side::Ask).is_none());
+
+    let nodes_to_fill = dlob.find_nodes_to_fill(0, MarketType::Perp, 0, &oracle_price_data);
+    assert_eq!(nodes_to_fill.len(), 1);
+    assert_eq!(nodes_to_fill[0].maker_nodes.len(), 1);
+    assert!(nodes_to_fill[0].maker_nodes[0].is_vamm_node());
+
+    dlob.set_vamm(0, None);
+    assert_eq!(
+        dlob.get_best_ask(0, MarketType::Perp, 0, &oracle_price_data),
+        Some(1_010_000)
+    );
+}
+
+#[test]
+fn tick_size_policy_rejects_or_rounds_mis_ticked_orders() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let tick_size = PRICE_PRECISION_U64 / 100;
+    dlob.set_tick_size(0, MarketType::Perp, Some(tick_size));
+
+    let on_tick = resting_limit_order(1, PositionDirection::Long, 99 * tick_size);
+    dlob.insert_order(on_tick, user, 0).unwrap();
+    let mis_ticked_bid = resting_limit_order(2, PositionDirection::Long, 99 * tick_size + 1);
+    assert_eq!(
+        dlob.insert_order(mis_ticked_bid, user, 0).unwrap_err(),
+        ErrorCode::InvalidOrder
+    );
+    assert!(dlob.get_node(2, user, 0, MarketType::Perp).is_none());
+
+    // other markets aren't checked
+    let other_market = Order {
+        market_index: 1,
+        ..mis_ticked_bid
+    };
+    dlob.insert_order(other_market, user, 0).unwrap();
+
+    dlob.set_tick_size_policy(TickSizePolicy::Round);
+    dlob.insert_order(mis_ticked_bid, user, 0).unwrap();
+    let mis_ticked_ask = resting_limit_order(3, PositionDirection::Short, 101 * tick_size + 1);
+    dlob.insert_order(mis_ticked_ask, user, 0).unwrap();
+
+    let price = |order_id| {
+        dlob.get_node(order_id, user, 0, MarketType::Perp)
+            .unwrap()
+            .order()
+            .unwrap()
+            .price
+    };
+    assert_eq!(price(2), 99 * tick_size);
+    assert_eq!(price(3), 102 * tick_size);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn init_from_json_loads_saved_orders() {
+    use crate::dlob_orders::DLOBOrder;
+
+    let user = Pubkey::new_unique();
+    let dlob_orders = vec![
+        DLOBOrder {
+            user,
+            order: resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64),
+        },
+        DLOBOrder {
+            user,
+            order: resting_limit_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64),
+        },
+    ];
+    let json = serde_json::to_string(&dlob_orders).unwrap();
+    assert!(json.contains(&user.to_string()));
+
+    let mut dlob = DLOB::new().unwrap();
+    assert!(dlob.init_from_json(&json, 0).unwrap());
+    let order = *dlob
+        .get_node(2, user, 0, MarketType::Perp)
+        .unwrap()
+        .order()
+        .unwrap();
+    assert_eq!(order, dlob_orders[1].order);
+
+    let mut dlob = DLOB::new().unwrap();
+    assert_eq!(
+        dlob.init_from_json("[{\"user\": \"not a pubkey\"}]", 0)
+            .unwrap_err(),
+        ErrorCode::InvalidOrder
+    );
+}
+
+#[test]
+fn handle_order_action_record_applies_each_action() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    let bid = Order {
+        base_asset_amount: 3 * BASE_PRECISION_U64,
+        ..resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64)
+    };
+    let cancelled = resting_limit_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    let expired = resting_limit_order(3, PositionDirection::Short, 102 * PRICE_PRECISION_U64);
+    let stop = OrderBuilder::new()
+        .order_id(4)
+        .order_type(OrderType::TriggerMarket)
+        .trigger(PRICE_PRECISION_U64, OrderTriggerCondition::Above)
+        .build();
+    for order in [bid, cancelled, expired, stop] {
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+    let order = |dlob: &DLOB, order_id| {
+        dlob.get_node(order_id, user, 0, MarketType::Perp)
+            .map(|node| *node.order().unwrap())
+    };
+
+    // placements come through `handle_order_record`, so this is a no-op
+    dlob.handle_order_action_record(order_action_record(OrderAction::Place, user, bid), 1)
+        .unwrap();
+    assert_eq!(order(&dlob, 1), Some(bid));
+
+    let fill = order_action_record(
+        OrderAction::Fill,
+        user,
+        Order {
+            base_asset_amount_filled: BASE_PRECISION_U64,
+            ..bid
+        },
+    );
+    dlob.handle_order_action_record(fill, 2).unwrap();
+    let filled = order(&dlob, 1).unwrap();
+    assert_eq!(
+        filled.base_asset_amount - filled.base_asset_amount_filled,
+        2 * BASE_PRECISION_U64
+    );
+
+    dlob.handle_order_action_record(order_action_record(OrderAction::Cancel, user, cancelled), 3)
+        .unwrap();
+    assert_eq!(order(&dlob, 2), None);
+
+    dlob.handle_order_action_record(order_action_record(OrderAction::Expire, user, expired), 4)
+        .unwrap();
+    assert_eq!(order(&dlob, 3), None);
+
+    assert_eq!(
+        dlob.trigger_distances(0, MarketType::Perp, &OraclePriceData::default_usd())
+            .len(),
+        1
+    );
+    dlob.handle_order_action_record(order_action_record(OrderAction::Trigger, user, stop), 5)
+        .unwrap();
+    assert_eq!(
+        order(&dlob, 4).unwrap().trigger_condition,
+        OrderTriggerCondition::TriggeredAbove
+    );
+    assert!(dlob
+        .trigger_distances(0, MarketType::Perp, &OraclePriceData::default_usd())
+        .is_empty());
+}
+
+#[test]
+fn crossable_size_counts_book_orders_the_vamm_absorbs() {
+    use drift::math::constants::{AMM_RESERVE_PRECISION, PEG_PRECISION};
+    use drift::state::perp_market::AMM;
+
+    use crate::dlob_node::VAMMNode;
+
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+    // quotes $0.999 / $1.001
+    let vamm = VAMMNode::new(
+        Side::Ask,
+        AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: PEG_PRECISION,
+            long_spread: 1_000,
+            short_spread: 1_000,
+            ..AMM::default()
+        },
+    );
+
+    let bids = [
+        (1, 102 * PRICE_PRECISION_U64 / 100, 2),
+        (2, 1_001_000, 1),
+        (3, PRICE_PRECISION_U64, 4),
+    ];
+    for (order_id, price, base) in bids {
+        let order = Order {
+            base_asset_amount: base * BASE_PRECISION_U64,
+            ..resting_limit_order(order_id, PositionDirection::Long, price)
+        };
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+    let ask = resting_limit_order(4, PositionDirection::Short, 103 * PRICE_PRECISION_U64 / 100);
+    dlob.insert_order(ask, user, 0).unwrap();
+
+    // the $1.02 and $1.001 bids cross the vAMM ask, the $1 bid and the ask don't
+    assert_eq!(
+        dlob.crossable_size(0, MarketType::Perp, 0, &oracle_price_data, &vamm),
+        3 * BASE_PRECISION_U64
+    );
+}
+
+#[test]
+fn public_mutations_keep_open_orders_in_sync() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let bid = resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+    let ask = resting_limit_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    dlob.insert_order(bid, user, 0).unwrap();
+    dlob.insert_order(ask, user, 0).unwrap();
+    assert_eq!(dlob.user_order_count(&user), 2);
+
+    let cancelled = Order {
+        status: OrderStatus::Canceled,
+        ..bid
+    };
+    dlob.insert_order(cancelled, user, 1).unwrap();
+    assert_eq!(dlob.user_order_count(&user), 1);
+
+    dlob.delete(ask, user, 2).unwrap();
+    assert_eq!(dlob.user_order_count(&user), 0);
+    assert!(dlob.get_node(2, user, 0, MarketType::Perp).is_none());
+}
+
+#[test]
+fn cancelled_insert_removes_the_resting_node() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+    let bid = resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+    dlob.insert_order(bid, user, 0).unwrap();
+    // still in its auction at slot 0, so it sits in the taking limit list
+    let taking_bid = OrderBuilder::new()
+        .order_id(2)
+        .price(98 * PRICE_PRECISION_U64)
+        .auction(0, 0, 10)
+        .build();
+    dlob.insert_order(taking_bid, user, 0).unwrap();
+
+    for order in [bid, taking_bid] {
+        let cancelled = Order {
+            status: OrderStatus::Canceled,
+            ..order
+        };
+        // by slot 20 the taking bid's auction is over, but its node is found all the same
+        dlob.insert_order(cancelled, user, 20).unwrap();
+    }
+
+    let l2 = dlob.get_l2_snapshot(0, MarketType::Perp, 20, &oracle_price_data, usize::MAX);
+    assert!(l2.bids.is_empty());
+    assert!(l2.asks.is_empty());
+    assert!(dlob.get_node(1, user, 0, MarketType::Perp).is_none());
+    assert!(dlob.get_node(2, user, 0, MarketType::Perp).is_none());
+    assert_eq!(dlob.user_order_count(&user), 0);
+}
+
+#[test]
+fn get_orders_for_user_filters_by_account() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let other_user = Pubkey::new_unique();
+
+    let bid = resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+    let ask = resting_limit_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    let spot_bid = OrderBuilder::new()
+        .order_id(3)
+        .market(0, MarketType::Spot)
+        .price(PRICE_PRECISION_U64)
+        .post_only(true)
+        .build();
+    dlob.insert_order(ask, user, 0).unwrap();
+    dlob.insert_order(bid, user, 0).unwrap();
+    dlob.insert_order(spot_bid, user, 0).unwrap();
+    dlob.insert_order(bid, other_user, 0).unwrap();
+
+    assert_eq!(dlob.get_orders_for_user(&user), vec![bid, ask, spot_bid]);
+    assert_eq!(dlob.get_orders_for_user(&other_user), vec![bid]);
+    assert!(dlob.get_orders_for_user(&Pubkey::new_unique()).is_empty());
+}
+
+#[test]
+fn open_orders_snapshot_round_trip() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    insert_two_sided_book(&mut dlob, user);
+
+    let snapshot = dlob.open_orders_snapshot();
+    assert_eq!(snapshot[&MarketType::Perp].len(), 2);
+    assert!(snapshot[&MarketType::Spot].is_empty());
+
+    dlob.delete(
+        resting_limit_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64),
+        user,
+        1,
+    )
+    .unwrap();
+    assert_eq!(dlob.user_order_count(&user), 1);
+
+    dlob.restore_open_orders(snapshot.clone());
+    assert_eq!(dlob.open_orders_snapshot(), snapshot);
+    assert_eq!(dlob.user_order_count(&user), 2);
+    // the restored signature for the deleted ask is stale
+    assert_eq!(dlob.reconcile_open_orders(), 1);
+}
+
+#[test]
+fn remove_orders_for_user_empties_their_lists() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let other_user = Pubkey::new_unique();
+
+    let bid = resting_limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64);
+    let ask = resting_limit_order(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64);
+    let spot_bid = OrderBuilder::new()
+        .order_id(3)
+        .market(0, MarketType::Spot)
+        .price(PRICE_PRECISION_U64)
+        .post_only(true)
+        .build();
+    for order in [bid, ask, spot_bid] {
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+    dlob.insert_order(bid, other_user, 0).unwrap();
+
+    assert_eq!(dlob.remove_orders_for_user(&user, 1).unwrap(), 3);
+    assert!(dlob.get_orders_for_user(&user).is_empty());
+    assert_eq!(dlob.user_order_count(&user), 0);
+    let remaining: usize = dlob.with_node_lists(|node_lists| {
+        node_lists
+            .iter()
+            .map(|node_list| node_list.iter().count())
+            .sum()
+    });
+    assert_eq!(remaining, 1);
+    assert_eq!(dlob.get_orders_for_user(&other_user), vec![bid]);
+
+    assert_eq!(dlob.remove_orders_for_user(&user, 1).unwrap(), 0);
+}
+
+#[test]
+fn preview_insert_reports_rank_without_inserting() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData::default_usd();
+
+    for (order_id, price) in [(1, 100), (2, 99), (3, 97)] {
+        dlob.insert_order(
+            resting_limit_order(
+                order_id,
+                PositionDirection::Long,
+                price * PRICE_PRECISION_U64,
+            ),
+            user,
+            0,
+        )
+        .unwrap();
+    }
+
+    let order = resting_limit_order(4, PositionDirection::Long, 98 * PRICE_PRECISION_U64);
+    let preview = dlob
+        .preview_insert(order, 0, &oracle_price_data)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        preview,
+        InsertPreview {
+            node_type: DLOBNodeType::RestingLimit,
+            side: Side::Bid,
+            rank: 2,
+            price: 98 * PRICE_PRECISION_U64 as i128,
+        }
+    );
+    assert!(dlob.get_node(4, user, 0, MarketType::Perp).is_none());
+
+    let spot_order = OrderBuilder::new()
+        .market(0, MarketType::Spot)
+        .price(PRICE_PRECISION_U64)
+        .build();
+    assert!(dlob
+        .preview_insert(spot_order, 0, &oracle_price_data)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn mutations_do_not_scale_with_book_size() {
+    use std::sync::Arc;
+
+    let reductions = 100;
+    for book_size in [10_u32, 5_000] {
+        let factory = Arc::new(CountingNodeFactory::default());
+        let mut dlob = DLOB::with_node_factory(factory.clone()).unwrap();
+        let user = Pubkey::new_unique();
+
+        let mut order = OrderBuilder::new()
+            .order_id(1)
+            .short()
+            .price(2 * book_size as u64 * PRICE_PRECISION_U64)
+            .base(10_000 * BASE_PRECISION_U64)
+            .post_only(true)
+            .build();
+        dlob.insert_order(order, user, 0).unwrap();
+        for order_id in 2..=book_size {
+            let price = (2 * book_size - order_id) as u64 * PRICE_PRECISION_U64;
+            dlob.insert_order(
+                resting_limit_order(order_id, PositionDirection::Short, price),
+                user,
+                0,
+            )
+            .unwrap();
+        }
+        let asks = |dlob: &DLOB| -> Vec<Arc<dyn DLOBNode>> {
+            dlob.get_side_list(0, MarketType::Perp, DLOBNodeType::RestingLimit, Side::Ask)
+                .unwrap()
+                .iter()
+                .collect()
+        };
+        let asks_before = asks(&dlob);
+
+        factory.reset();
+        for _ in 0..reductions {
+            let reduced = order.base_asset_amount - BASE_PRECISION_U64;
+            dlob.reduce_order(order, user, reduced, 0).unwrap();
+            order.base_asset_amount = reduced;
+        }
+
+        // each reduction builds just the replacement node, without pricing or copying the
+        // rest of the book
+        assert_eq!(factory.created(), reductions);
+        assert_eq!(factory.get_price_calls(), 0);
+
+        let asks_after = asks(&dlob);
+        assert_eq!(asks_after.len(), book_size as usize);
+        for (before, after) in asks_before.iter().zip(&asks_after) {
+            if after.order().unwrap().order_id == 1 {
+                assert_eq!(after.order(), Some(&order));
+            } else {
+                assert!(std::ptr::eq(
+                    Arc::as_ptr(before) as *const (),
+                    Arc::as_ptr(after) as *const ()
+                ));
+            }
+        }
+    }
+}
+
+#[test]
+fn sort_direction_per_node_type_and_side() {
+    for node_type in [
+        DLOBNodeType::RestingLimit,
+        DLOBNodeType::TakingLimit,
+        DLOBNodeType::FloatingLimit,
+        DLOBNodeType::Trigger,
+    ] {
+        assert_eq!(
+            sort_direction_for(node_type.clone(), Side::Bid),
+            SortDirection::Desc
+        );
+        assert_eq!(sort_direction_for(node_type, Side::Ask), SortDirection::Asc);
+    }
+
+    assert_eq!(
+        sort_direction_for(DLOBNodeType::Market, Side::Bid),
+        SortDirection::Asc
+    );
+    assert_eq!(
+        sort_direction_for(DLOBNodeType::Market, Side::Ask),
+        SortDirection::Asc
+    );
+}
+
+#[test]
+fn inserted_order_is_stored_in_the_book() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let order = OrderBuilder::new()
+        .order_id(1)
+        .order_type(OrderType::TriggerMarket)
+        .trigger(101 * PRICE_PRECISION_U64, OrderTriggerCondition::Above)
+        .build();
+
+    dlob.insert_order(order, user, 0).unwrap();
+
+    assert_eq!(dlob.get_order(1, user).unwrap(), Some(order));
+    assert_eq!(dlob.get_order(2, user).unwrap(), None);
+
+    dlob.delete(order, user, 0).unwrap();
+    assert_eq!(dlob.get_order(1, user).unwrap(), None);
+}
+
+#[test]
+fn time_to_fill_drains_queue_at_recent_fill_rate() {
+    use std::time::Duration;
+
+    let mut dlob = DLOB::new().unwrap();
+    let oracle_price_data = OraclePriceData::default_usd();
+    let user = Pubkey::new_unique();
+
+    for (order_id, price) in [(1, 100), (2, 101), (3, 102)] {
+        dlob.insert_order(
+            resting_limit_order(
+                order_id,
+                PositionDirection::Short,
+                price * PRICE_PRECISION_U64,
+            ),
+            user,
+            0,
+        )
+        .unwrap();
+    }
+    let order_signature = get_order_signature(3, &user);
+    assert_eq!(
+        dlob.estimate_time_to_fill(&order_signature, &oracle_price_data),
+        None
+    );
+
+    // 3 base filled over the 150 slot window is 1 base every 50 slots
+    let bid = OrderBuilder::new()
+        .order_id(9)
+        .price(90 * PRICE_PRECISION_U64)
+        .base(10 * BASE_PRECISION_U64)
+        .post_only(true)
+        .build();
+    dlob.insert_order(bid, user, 0).unwrap();
+    dlob.update_order(bid, user, 150, 3 * BASE_PRECISION_U64)
+        .unwrap();
+
+    // two asks ahead plus its own base: 150 slots of 400ms
+    assert_eq!(
+        dlob.estimate_time_to_fill(&order_signature, &oracle_price_data),
+        Some(Duration::from_secs(60))
+    );
+    assert_eq!(
+        dlob.estimate_time_to_fill(&get_order_signature(4, &user), &oracle_price_data),
+        None
+    );
+}
+
+#[test]
+fn get_order_finds_known_ids_only() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let order = resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+    dlob.insert_order(order, user, 0).unwrap();
+
+    assert_eq!(dlob.get_order(1, user).unwrap(), Some(order));
+    assert_eq!(dlob.get_order(2, user).unwrap(), None);
+    assert_eq!(dlob.get_order(1, Pubkey::new_unique()).unwrap(), None);
+}
+
+#[test]
+fn top_of_book_scenarios_reprice_floating_and_trigger_orders() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    let resting_bid = resting_limit_order(
+        1,
+        PositionDirection::Long,
+        99 * PRICE_PRECISION_U64 + PRICE_PRECISION_U64 / 2,
+    );
+    let floating_bid = OrderBuilder::new()
+        .order_id(2)
+        .oracle_price_offset(-(PRICE_PRECISION_U64 as i32))
+        .post_only(true)
+        .build();
+    let resting_ask = resting_limit_order(3, PositionDirection::Short, 104 * PRICE_PRECISION_U64);
+    let stop_ask = OrderBuilder::new()
+        .order_id(4)
+        .short()
+        .order_type(OrderType::TriggerLimit)
+        .price(103 * PRICE_PRECISION_U64)
+        .trigger(101 * PRICE_PRECISION_U64, OrderTriggerCondition::Above)
+        .build();
+    for order in [resting_bid, floating_bid, resting_ask, stop_ask] {
+        dlob.insert_order(order, user, 0).unwrap();
+    }
+
+    let oracle_prices = [
+        100 * PRICE_PRECISION_U64 as i128,
+        102 * PRICE_PRECISION_U64 as i128,
+    ];
+    let scenarios = dlob.top_of_book_scenarios(0, MarketType::Perp, &oracle_prices, 0);
+
+    assert_eq!(
+        scenarios,
+        vec![
+            // the floating bid sits at 99, behind the resting bid
+            TopOfBook {
+                oracle_price: oracle_prices[0],
+                best_bid: Some((99 * PRICE_PRECISION_U64 + PRICE_PRECISION_U64 / 2) as i128),
+                best_ask: Some(104 * PRICE_PRECISION_U64 as i128),
+            },
+            // at 102 the floating bid moves to 101 and the stop ask triggers
+            TopOfBook {
+                oracle_price: oracle_prices[1],
+                best_bid: Some(101 * PRICE_PRECISION_U64 as i128),
+                best_ask: Some(103 * PRICE_PRECISION_U64 as i128),
+            },
+        ]
+    );
+}
+
+#[test]
+fn bids_iterate_highest_first_in_resting_and_taking_lists() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+
+    for (order_id, price) in [(1, 99), (2, 101), (3, 100)] {
+        dlob.insert_order(
+            resting_limit_order(
+                order_id,
+                PositionDirection::Long,
+                price * PRICE_PRECISION_U64,
+            ),
+            user,
+            0,
+        )
+        .unwrap();
+        let price = price * PRICE_PRECISION_U64;
+        let taking_bid = OrderBuilder::new()
+            .order_id(order_id + 10)
+            .price(price)
+            .auction(price as i64, price as i64, 10)
+            .build();
+        dlob.insert_order(taking_bid, user, 0).unwrap();
+    }
+
+    for node_type in [DLOBNodeType::RestingLimit, DLOBNodeType::TakingLimit] {
+        let prices: Vec<u64> = dlob
+            .get_side_list(0, MarketType::Perp, node_type, Side::Bid)
+            .unwrap()
+            .iter()
+            .map(|node| node.order().unwrap().price)
+            .collect();
+        assert_eq!(
+            prices,
+            vec![
+                101 * PRICE_PRECISION_U64,
+                100 * PRICE_PRECISION_U64,
+                99 * PRICE_PRECISION_U64
+            ]
+        );
+    }
+}
+
+#[test]
+fn find_duplicate_nodes_reports_order_in_two_lists() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let taking_bid = OrderBuilder::new()
+        .order_id(1)
+        .price(100 * PRICE_PRECISION_U64)
+        .auction(0, 0, 10)
+        .build();
+    dlob.insert_order(taking_bid, user, 0).unwrap();
+    dlob.insert_order(
+        resting_limit_order(2, PositionDirection::Long, 99 * PRICE_PRECISION_U64),
+        user,
+        0,
+    )
+    .unwrap();
+    assert!(dlob.find_duplicate_nodes().is_empty());
+
+    // link the taking order into the resting list too, as a missed removal would
+    dlob.get_list_for_order_mut(taking_bid, 100)
+        .unwrap()
+        .insert(taking_bid, user)
+        .unwrap();
+
+    let duplicates = dlob.find_duplicate_nodes();
+    assert_eq!(duplicates.len(), 1);
+    let (order_signature, node_types) = &duplicates[0];
+    assert_eq!(order_signature, &get_order_signature(1, &user));
+    assert_eq!(node_types.len(), 2);
+    assert!(node_types.contains(&DLOBNodeType::TakingLimit));
+    assert!(node_types.contains(&DLOBNodeType::RestingLimit));
+}
+
+#[test]
+fn added_market_keeps_all_of_its_lists() {
+    let mut dlob = DLOB::new().unwrap();
+    dlob.add_order_list(MarketType::Perp, 3);
+
+    for node_type in [
+        DLOBNodeType::RestingLimit,
+        DLOBNodeType::FloatingLimit,
+        DLOBNodeType::TakingLimit,
+        DLOBNodeType::Market,
+    ] {
+        for side in [Side::Bid, Side::Ask] {
+            assert!(dlob
+                .get_side_list(3, MarketType::Perp, node_type.clone(), side)
+                .is_some());
+        }
+    }
+    assert_eq!(dlob.get_market_node_lists(3, MarketType::Perp).len(), 10);
+
+    let user = Pubkey::new_unique();
+    let bid = Order {
+        market_index: 3,
+        ..resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+    dlob.insert_order(bid, user, 0).unwrap();
+    let resting_bids = dlob
+        .get_side_list(3, MarketType::Perp, DLOBNodeType::RestingLimit, Side::Bid)
+        .unwrap();
+    assert_eq!(
+        resting_bids
+            .get(&get_order_signature(1, &user))
+            .unwrap()
+            .order(),
+        Some(&bid)
+    );
+}
+
+#[test]
+fn order_remaining_tracks_cumulative_fills() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let bid = Order {
+        base_asset_amount: 10 * BASE_PRECISION_U64,
+        ..resting_limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+    dlob.insert_order(bid, user, 0).unwrap();
+    let remaining = |dlob: &DLOB| dlob.order_remaining(1, user, 0, MarketType::Perp);
+    assert_eq!(remaining(&dlob), Some(10 * BASE_PRECISION_U64));
+
+    dlob.update_order(bid, user, 1, 3 * BASE_PRECISION_U64)
+        .unwrap();
+    assert_eq!(remaining(&dlob), Some(7 * BASE_PRECISION_U64));
+
+    let stored = dlob.get_order(1, user).unwrap().unwrap();
+    assert_eq!(stored.base_asset_amount, 10 * BASE_PRECISION_U64);
+    dlob.update_order(stored, user, 2, 5 * BASE_PRECISION_U64)
+        .unwrap();
+    assert_eq!(remaining(&dlob), Some(5 * BASE_PRECISION_U64));
+
+    dlob.update_order(bid, user, 3, 10 * BASE_PRECISION_U64)
+        .unwrap();
+    assert_eq!(remaining(&dlob), None);
+}
+
+#[test]
+fn get_l2_round_to_tick_merges_near_identical_levels() {
+    let mut dlob = DLOB::new().unwrap();
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData {
+        price: 100 * PRICE_PRECISION_U64 as i64,
+        ..OraclePriceData::default_usd()
+    };
+    let tick_size = PRICE_PRECISION_U64 / 1_000;
+
+    // 100.0004 and 100.0007, both inside the 100.000 bid tick
+    for (order_id, oracle_price_offset) in [(1, 400), (2, 700)] {
+        let floating_bid = OrderBuilder::new()
+            .order_id(order_id)
+            .oracle_price_offset(oracle_price_offset)
+            .post_only(true)
+            .build();
+        dlob.insert_order(floating_bid, user, 0).unwrap();
+    }
+    let l2 = |round_to_tick| {
+        dlob.get_l2(
+            0,
+            MarketType::Perp,
+            0,
+            &oracle_price_data,
+            L2Options {
+                depth: 10,
+                round_to_tick,
+                ..L2Options::default()
+            },
+        )
+    };
+
+    assert_eq!(l2(None).bids.len(), 2);
+
+    let rounded = l2(Some(tick_size));
+    assert_eq!(
+        rounded.bids,
+        vec![L2Level {
+            price: 100 * PRICE_PRECISION_U64 as i128,
+            size: 2 * BASE_PRECISION_U64,
+            num_orders: 2,
+            cumulative_size: 0,
+        }]
+    );
+}
@@ -6,11 +6,19 @@ use drift::{
     math::constants::{AMM_RESERVE_PRECISION, PRICE_PRECISION},
     state::{
         oracle::OraclePriceData,
-        user::{Order, OrderTriggerCondition},
+        perp_market::AMM,
+        user::{Order, OrderTriggerCondition, OrderType},
     },
 };
 
-use crate::{conversion::convert_to_number, node_list::get_order_signature};
+use crate::{
+    conversion::{convert_to_number, convert_to_number_i128, price_to_bps_diff},
+    dlob::Side,
+    node_list::get_order_signature,
+};
+
+#[cfg(test)]
+mod tests;
 
 pub trait DLOBNode: Debug {
     fn get_price(&self, oracle_price_data: &OraclePriceData, slot: u64) -> i128;
@@ -20,6 +28,55 @@ pub trait DLOBNode: Debug {
     fn order(&self) -> Option<&Order>;
     fn user_account(&self) -> Option<&Pubkey>;
     fn sort_value(&self) -> i128;
+
+    /// Whether this node already holds `order` for `user_account`, i.e. applying it as an
+    /// update would not change anything.
+    fn same_state(&self, order: &Order, user_account: &Pubkey) -> bool {
+        self.order() == Some(order) && self.user_account() == Some(user_account)
+    }
+
+    /// Remaining base times the effective price, in QUOTE_PRECISION.
+    fn remaining_notional(&self, oracle_price_data: &OraclePriceData, slot: u64) -> u128 {
+        let remaining_base = self.order().map_or(0, |order| {
+            order
+                .base_asset_amount
+                .saturating_sub(order.base_asset_amount_filled)
+        });
+        let price = self.get_price(oracle_price_data, slot).max(0) as u128;
+
+        (remaining_base as u128).saturating_mul(price) / AMM_RESERVE_PRECISION
+    }
+
+    /// Rough chance of this order filling given the current `top_of_book` price on its side:
+    /// 1 at or through the top, decaying exponentially with the distance behind it measured
+    /// in units of `volatility_bps`. Orders without a limit price (market and floating) count
+    /// as at the top. Implementors can override this with a better model.
+    fn estimate_fill_probability(&self, top_of_book: i128, volatility_bps: u32) -> f64 {
+        let order = match self.order() {
+            Some(order) if order.price > 0 => order,
+            _ => return 1.0,
+        };
+
+        let bps_from_top = price_to_bps_diff(order.price as i128, top_of_book);
+        let bps_behind_top = match order.direction {
+            PositionDirection::Long => -bps_from_top,
+            PositionDirection::Short => bps_from_top,
+        };
+        if bps_behind_top <= 0 {
+            return 1.0;
+        }
+        if volatility_bps == 0 {
+            return 0.0;
+        }
+
+        (-(bps_behind_top as f64) / volatility_bps as f64).exp()
+    }
+
+    /// The `OrderNode` backing this node, for reading variant-specific state such as
+    /// `have_trigger`. Nodes that aren't built on an `OrderNode` return `None`.
+    fn as_order_node(&self) -> Option<&OrderNode> {
+        None
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -27,6 +84,8 @@ pub struct OrderNode {
     pub order: Order,
     pub user_account: Pubkey,
     pub sort_value: i128,
+    /// FIFO position, taken from the order's slot.
+    pub sequence: u64,
     pub have_filled: bool,
     pub have_trigger: bool,
 }
@@ -38,8 +97,9 @@ impl OrderNode {
             order,
             user_account,
             sort_value,
+            sequence: order.slot,
             have_filled: false,
-            have_trigger: false,
+            have_trigger: order.must_be_triggered() && order.triggered(),
         }
     }
 
@@ -47,6 +107,21 @@ impl OrderNode {
         order.price as i128
     }
 
+    /// Sorts the node by `sequence` instead of price, for the FIFO market lists. Taking
+    /// limit lists stay price-sorted since their bids must iterate highest price first
+    /// like every other bid list.
+    pub fn sorted_by_sequence(mut self) -> Self {
+        self.sort_value = self.sequence as i128;
+        self
+    }
+
+    /// Sorts the node by `oracle_price_offset` instead of price, for the floating limit
+    /// lists whose orders carry no fixed price.
+    pub fn sorted_by_oracle_offset(mut self) -> Self {
+        self.sort_value = self.order.oracle_price_offset as i128;
+        self
+    }
+
     pub fn get_label(&self) -> String {
         let mut msg = format!(
             "Order {}",
@@ -66,6 +141,11 @@ impl OrderNode {
                 " @ {:.3}",
                 convert_to_number(self.order.price, PRICE_PRECISION)
             );
+        } else if self.order.oracle_price_offset != 0 {
+            msg += &format!(
+                " @ oracle{:+.3}",
+                convert_to_number_i128(self.order.oracle_price_offset as i128, PRICE_PRECISION)
+            );
         }
         if self.order.trigger_price > 0 {
             msg += match self.order.trigger_condition {
@@ -81,6 +161,12 @@ impl OrderNode {
     }
 }
 
+impl PartialEq for OrderNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.order == other.order && self.user_account == other.user_account
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum DLOBNodeOrders {
     RestingLimit(OrderNode),
@@ -90,9 +176,32 @@ pub enum DLOBNodeOrders {
     Trigger(OrderNode),
 }
 
+impl DLOBNodeOrders {
+    pub fn as_inner(&self) -> &OrderNode {
+        match self {
+            DLOBNodeOrders::RestingLimit(order_node)
+            | DLOBNodeOrders::TakingLimit(order_node)
+            | DLOBNodeOrders::FloatingLimit(order_node)
+            | DLOBNodeOrders::Market(order_node)
+            | DLOBNodeOrders::Trigger(order_node) => order_node,
+        }
+    }
+}
+
 impl DLOBNode for DLOBNodeOrders {
     fn get_price(&self, oracle_price_data: &OraclePriceData, slot: u64) -> i128 {
-        oracle_price_data.price as i128
+        match self {
+            // a large negative offset can't take a floating order to a non-positive price
+            DLOBNodeOrders::FloatingLimit(order_node) => (oracle_price_data.price as i128
+                + order_node.order.oracle_price_offset as i128)
+                .max(1),
+            DLOBNodeOrders::TakingLimit(order_node) | DLOBNodeOrders::Market(order_node) => {
+                get_auction_price(&order_node.order, slot, oracle_price_data.price as i128)
+            }
+            DLOBNodeOrders::RestingLimit(order_node) | DLOBNodeOrders::Trigger(order_node) => {
+                order_node.order.price as i128
+            }
+        }
     }
 
     fn is_vamm_node(&self) -> bool {
@@ -151,6 +260,112 @@ impl DLOBNode for DLOBNodeOrders {
             | DLOBNodeOrders::Trigger(order_node) => order_node.sort_value,
         }
     }
+
+    fn as_order_node(&self) -> Option<&OrderNode> {
+        Some(self.as_inner())
+    }
+}
+
+/// One side of a perp market's vAMM quoting alongside the book. The price comes from the
+/// AMM's reserves and spread, falling back to the oracle if the reserves can't be priced.
+#[derive(Debug, Clone)]
+pub struct VAMMNode {
+    pub side: Side,
+    pub amm: AMM,
+}
+
+impl VAMMNode {
+    pub fn new(side: Side, amm: AMM) -> Self {
+        Self { side, amm }
+    }
+}
+
+impl DLOBNode for VAMMNode {
+    fn get_price(&self, oracle_price_data: &OraclePriceData, _slot: u64) -> i128 {
+        self.amm
+            .reserve_price()
+            .and_then(|reserve_price| match self.side {
+                Side::Bid => self.amm.bid_price(reserve_price),
+                Side::Ask => self.amm.ask_price(reserve_price),
+            })
+            .map_or(oracle_price_data.price as i128, |price| price as i128)
+    }
+
+    fn is_vamm_node(&self) -> bool {
+        true
+    }
+
+    fn is_base_filled(&self) -> bool {
+        false
+    }
+
+    fn have_filled(&self) -> bool {
+        false
+    }
+
+    fn order(&self) -> Option<&Order> {
+        None
+    }
+
+    fn user_account(&self) -> Option<&Pubkey> {
+        None
+    }
+
+    fn sort_value(&self) -> i128 {
+        0
+    }
+}
+
+/// Price of `order` at `slot` during its auction, moving linearly from
+/// `auction_start_price` to `auction_end_price` over `auction_duration` slots. Oracle orders
+/// auction at offsets from `oracle_price`. Once the auction is over (as drift's
+/// `is_auction_complete` decides) this is the order's limit price, which for oracle orders
+/// is `oracle_price` plus their `oracle_price_offset`.
+pub fn get_auction_price(order: &Order, slot: u64, oracle_price: i128) -> i128 {
+    let slots_elapsed = slot.saturating_sub(order.slot);
+    if order.auction_duration == 0 || slots_elapsed > order.auction_duration as u64 {
+        return if order.order_type == OrderType::Oracle {
+            oracle_price + order.oracle_price_offset as i128
+        } else {
+            order.price as i128
+        };
+    }
+
+    let start_price = order.auction_start_price as i128;
+    let end_price = order.auction_end_price as i128;
+    let price = start_price
+        + (end_price - start_price) * slots_elapsed as i128 / order.auction_duration as i128;
+
+    if order.order_type == OrderType::Oracle {
+        oracle_price + price
+    } else {
+        price
+    }
+}
+
+/// Builds the nodes stored in a `NodeList`, letting downstream crates wrap or replace the
+/// default `DLOBNodeOrders` nodes with their own `DLOBNode` implementations.
+pub trait NodeFactory: Debug {
+    fn create_node(
+        &self,
+        node_type: DLOBNodeType,
+        order: Order,
+        user_account: Pubkey,
+    ) -> Arc<dyn DLOBNode>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultNodeFactory;
+
+impl NodeFactory for DefaultNodeFactory {
+    fn create_node(
+        &self,
+        node_type: DLOBNodeType,
+        order: Order,
+        user_account: Pubkey,
+    ) -> Arc<dyn DLOBNode> {
+        create_node(node_type, order, user_account)
+    }
 }
 
 pub fn create_node(
@@ -162,8 +377,10 @@ pub fn create_node(
     let node = match node_type {
         DLOBNodeType::RestingLimit => DLOBNodeOrders::RestingLimit(order_node),
         DLOBNodeType::TakingLimit => DLOBNodeOrders::TakingLimit(order_node),
-        DLOBNodeType::FloatingLimit => DLOBNodeOrders::FloatingLimit(order_node),
-        DLOBNodeType::Market => DLOBNodeOrders::Market(order_node),
+        DLOBNodeType::FloatingLimit => {
+            DLOBNodeOrders::FloatingLimit(order_node.sorted_by_oracle_offset())
+        }
+        DLOBNodeType::Market => DLOBNodeOrders::Market(order_node.sorted_by_sequence()),
         DLOBNodeType::Trigger => DLOBNodeOrders::Trigger(order_node),
     };
     Arc::new(node)
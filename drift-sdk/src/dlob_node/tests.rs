@@ -0,0 +1,286 @@
+use anchor_lang::prelude::Pubkey;
+use drift::math::constants::{
+    AMM_RESERVE_PRECISION, BASE_PRECISION_U64, PEG_PRECISION, PRICE_PRECISION_U64, QUOTE_PRECISION,
+};
+use drift::state::oracle::OraclePriceData;
+use drift::state::perp_market::AMM;
+use drift::state::user::OrderType;
+
+use crate::dlob::Side;
+use crate::dlob_node::{
+    create_node, get_auction_price, DLOBNode, DLOBNodeOrders, DLOBNodeType, OrderNode, VAMMNode,
+};
+use crate::test_utils::OrderBuilder;
+
+#[test]
+fn as_order_node_downcasts_to_variant_data() {
+    let user = Pubkey::new_unique();
+    let order = OrderBuilder::new()
+        .order_id(1)
+        .price(PRICE_PRECISION_U64)
+        .build();
+
+    let node = create_node(DLOBNodeType::Trigger, order, user);
+    let order_node = node.as_order_node().unwrap();
+    assert!(!order_node.have_filled);
+    assert!(!order_node.have_trigger);
+    assert_eq!(order_node.order, order);
+
+    let mut filled_node = OrderNode::new(order, user);
+    filled_node.have_filled = true;
+    let node_orders = DLOBNodeOrders::RestingLimit(filled_node);
+    assert!(node_orders.as_inner().have_filled);
+    assert_eq!(node_orders.as_inner().user_account, user);
+}
+
+#[test]
+fn get_price_per_variant() {
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData {
+        price: 20 * PRICE_PRECISION_U64 as i64,
+        ..OraclePriceData::default_usd()
+    };
+    let limit_order = OrderBuilder::new().price(21 * PRICE_PRECISION_U64).build();
+    let limit_price = 21 * PRICE_PRECISION_U64 as i128;
+
+    for node_type in [
+        DLOBNodeType::RestingLimit,
+        DLOBNodeType::TakingLimit,
+        DLOBNodeType::Trigger,
+    ] {
+        let node = create_node(node_type, limit_order, user);
+        assert_eq!(node.get_price(&oracle_price_data, 0), limit_price);
+    }
+
+    let market_order = OrderBuilder::new()
+        .order_type(OrderType::Market)
+        .price(19 * PRICE_PRECISION_U64)
+        .build();
+    let node = create_node(DLOBNodeType::Market, market_order, user);
+    assert_eq!(
+        node.get_price(&oracle_price_data, 0),
+        19 * PRICE_PRECISION_U64 as i128
+    );
+
+    let floating_order = OrderBuilder::new()
+        .oracle_price_offset(-(PRICE_PRECISION_U64 as i32))
+        .build();
+    let node = create_node(DLOBNodeType::FloatingLimit, floating_order, user);
+    assert_eq!(
+        node.get_price(&oracle_price_data, 0),
+        19 * PRICE_PRECISION_U64 as i128
+    );
+}
+
+#[test]
+fn floating_price_applies_offset_and_clamps() {
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData {
+        price: 20 * PRICE_PRECISION_U64 as i64,
+        ..OraclePriceData::default_usd()
+    };
+    let floating_price = |oracle_price_offset: i32| {
+        let order = OrderBuilder::new()
+            .oracle_price_offset(oracle_price_offset)
+            .build();
+        create_node(DLOBNodeType::FloatingLimit, order, user).get_price(&oracle_price_data, 0)
+    };
+
+    assert_eq!(floating_price(250_000), 20_250_000);
+    assert_eq!(floating_price(-250_000), 19_750_000);
+    assert_eq!(floating_price(-20 * PRICE_PRECISION_U64 as i32), 1);
+    assert_eq!(floating_price(-25 * PRICE_PRECISION_U64 as i32), 1);
+}
+
+#[test]
+fn remaining_notional_uses_remaining_base_and_price() {
+    let order = OrderBuilder::new()
+        .price(25 * PRICE_PRECISION_U64)
+        .base(4 * BASE_PRECISION_U64)
+        .filled(BASE_PRECISION_U64 / 2)
+        .build();
+    let node = create_node(DLOBNodeType::RestingLimit, order, Pubkey::new_unique());
+
+    // 3.5 * 25
+    assert_eq!(
+        node.remaining_notional(&OraclePriceData::default_usd(), 0),
+        875 * QUOTE_PRECISION / 10
+    );
+}
+
+#[test]
+fn auction_price_interpolates_over_duration() {
+    let user = Pubkey::new_unique();
+    let oracle_price = 20 * PRICE_PRECISION_U64 as i128;
+    let order = OrderBuilder::new()
+        .order_type(OrderType::Market)
+        .price(22 * PRICE_PRECISION_U64)
+        .auction(
+            20 * PRICE_PRECISION_U64 as i64,
+            21 * PRICE_PRECISION_U64 as i64,
+            10,
+        )
+        .slot(100)
+        .build();
+
+    assert_eq!(
+        get_auction_price(&order, 100, oracle_price),
+        20 * PRICE_PRECISION_U64 as i128
+    );
+    assert_eq!(
+        get_auction_price(&order, 105, oracle_price),
+        (20 * PRICE_PRECISION_U64 + PRICE_PRECISION_U64 / 2) as i128
+    );
+    // the last auction slot still prices at the end price
+    assert_eq!(
+        get_auction_price(&order, 110, oracle_price),
+        21 * PRICE_PRECISION_U64 as i128
+    );
+    // the auction is over, so the limit price applies
+    assert_eq!(
+        get_auction_price(&order, 111, oracle_price),
+        22 * PRICE_PRECISION_U64 as i128
+    );
+
+    let oracle_order = OrderBuilder::new()
+        .order_type(OrderType::Oracle)
+        .auction(0, PRICE_PRECISION_U64 as i64, 10)
+        .slot(100)
+        .build();
+    assert_eq!(
+        get_auction_price(&oracle_order, 105, oracle_price),
+        oracle_price + (PRICE_PRECISION_U64 / 2) as i128
+    );
+
+    let oracle_price_data = OraclePriceData {
+        price: oracle_price as i64,
+        ..OraclePriceData::default_usd()
+    };
+    for node_type in [DLOBNodeType::Market, DLOBNodeType::TakingLimit] {
+        let node = create_node(node_type, order, user);
+        assert_eq!(
+            node.get_price(&oracle_price_data, 105),
+            (20 * PRICE_PRECISION_U64 + PRICE_PRECISION_U64 / 2) as i128
+        );
+    }
+}
+
+#[test]
+fn auction_price_after_oracle_auction_tracks_oracle() {
+    let oracle_price = 20 * PRICE_PRECISION_U64 as i128;
+    let offset = PRICE_PRECISION_U64 as i32 / 4;
+    let oracle_order = OrderBuilder::new()
+        .order_type(OrderType::Oracle)
+        .oracle_price_offset(offset)
+        .auction(0, PRICE_PRECISION_U64 as i64, 10)
+        .slot(100)
+        .build();
+
+    assert_eq!(
+        get_auction_price(&oracle_order, 111, oracle_price),
+        oracle_price + offset as i128
+    );
+    // the limit follows the oracle rather than staying where the auction ended
+    assert_eq!(
+        get_auction_price(&oracle_order, 111, 2 * oracle_price),
+        2 * oracle_price + offset as i128
+    );
+
+    // no auction at all is complete straight away instead of dividing by zero
+    let no_auction = OrderBuilder::new()
+        .order_type(OrderType::Oracle)
+        .oracle_price_offset(offset)
+        .slot(100)
+        .build();
+    assert_eq!(
+        get_auction_price(&no_auction, 100, oracle_price),
+        oracle_price + offset as i128
+    );
+}
+
+/// AMM priced at $1 from equal reserves and a 1:1 peg, quoting 0.1% either side.
+fn synthetic_amm() -> AMM {
+    AMM {
+        base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+        quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+        peg_multiplier: PEG_PRECISION,
+        long_spread: 1_000,
+        short_spread: 1_000,
+        ..AMM::default()
+    }
+}
+
+#[test]
+fn vamm_node_prices_from_reserves() {
+    let oracle_price_data = OraclePriceData {
+        price: 2 * PRICE_PRECISION_U64 as i64,
+        ..OraclePriceData::default_usd()
+    };
+
+    let bid = VAMMNode::new(Side::Bid, synthetic_amm());
+    let ask = VAMMNode::new(Side::Ask, synthetic_amm());
+    assert!(bid.is_vamm_node());
+    assert!(bid.order().is_none());
+    assert_eq!(bid.get_price(&oracle_price_data, 0), 999_000);
+    assert_eq!(ask.get_price(&oracle_price_data, 0), 1_001_000);
+
+    // reserves that can't be priced fall back to the oracle
+    let unpriced = VAMMNode::new(Side::Bid, AMM::default());
+    assert_eq!(
+        unpriced.get_price(&oracle_price_data, 0),
+        2 * PRICE_PRECISION_U64 as i128
+    );
+}
+
+#[test]
+fn fill_probability_falls_with_distance_from_top() {
+    let user = Pubkey::new_unique();
+    let top_of_book = 100 * PRICE_PRECISION_U64 as i128;
+    let bid = |price| {
+        create_node(
+            DLOBNodeType::RestingLimit,
+            OrderBuilder::new().price(price).post_only(true).build(),
+            user,
+        )
+    };
+
+    let top = bid(100 * PRICE_PRECISION_U64);
+    let near = bid(99 * PRICE_PRECISION_U64);
+    let deep = bid(90 * PRICE_PRECISION_U64);
+
+    let top_probability = top.estimate_fill_probability(top_of_book, 100);
+    let near_probability = near.estimate_fill_probability(top_of_book, 100);
+    let deep_probability = deep.estimate_fill_probability(top_of_book, 100);
+    assert_eq!(top_probability, 1.0);
+    assert!(top_probability > near_probability);
+    assert!(near_probability > deep_probability);
+    assert!(deep_probability > 0.0);
+
+    // an ask above the top ask is behind it too
+    let ask = create_node(
+        DLOBNodeType::RestingLimit,
+        OrderBuilder::new()
+            .short()
+            .price(101 * PRICE_PRECISION_U64)
+            .post_only(true)
+            .build(),
+        user,
+    );
+    assert!(ask.estimate_fill_probability(top_of_book, 100) < 1.0);
+    assert_eq!(near.estimate_fill_probability(top_of_book, 0), 0.0);
+}
+
+#[test]
+fn floating_label_shows_signed_oracle_offset() {
+    let user = Pubkey::new_unique();
+    let label = |oracle_price_offset: i32| {
+        let order = OrderBuilder::new()
+            .order_id(1)
+            .oracle_price_offset(oracle_price_offset)
+            .build();
+        OrderNode::new(order, user).get_label()
+    };
+
+    assert!(label(-250_000).ends_with(" @ oracle-0.250"));
+    assert!(label(250_000).ends_with(" @ oracle+0.250"));
+}
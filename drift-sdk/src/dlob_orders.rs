@@ -5,7 +5,44 @@ use drift::state::user::Order;
 pub type DLOBOrders = Vec<DLOBOrder>;
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DLOBOrder {
+    #[cfg_attr(feature = "serde", serde(with = "pubkey_base58"))]
     pub user: Pubkey,
+    #[cfg_attr(feature = "serde", serde(with = "order_bytes"))]
     pub order: Order,
 }
+
+/// (De)serializes a `Pubkey` as its base58 string.
+#[cfg(feature = "serde")]
+mod pubkey_base58 {
+    use std::str::FromStr;
+
+    use anchor_lang::prelude::Pubkey;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(pubkey: &Pubkey, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&pubkey.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pubkey, D::Error> {
+        let pubkey = String::deserialize(deserializer)?;
+        Pubkey::from_str(&pubkey).map_err(Error::custom)
+    }
+}
+
+/// (De)serializes an `Order` as the bytes of its zero-copy layout.
+#[cfg(feature = "serde")]
+mod order_bytes {
+    use drift::state::user::Order;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(order: &Order, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytemuck::bytes_of(order))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Order, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        bytemuck::try_pod_read_unaligned(&bytes).map_err(Error::custom)
+    }
+}
@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+
+use crate::dlob::MarketId;
+use crate::l2::L2OrderBook;
+
+/// Immutable copy of the book's price levels, built by `DLOB::freeze`.
+///
+/// Holds only plain data so it is `Send + Sync` and can be shared behind an `Arc` by a hot
+/// quoting loop while the live `DLOB` keeps mutating; reads never allocate or lock.
+#[derive(Debug, Clone, Default)]
+pub struct FrozenDlob {
+    pub(crate) slot: u64,
+    pub(crate) books: HashMap<MarketId, L2OrderBook>,
+}
+
+impl FrozenDlob {
+    /// Slot the snapshot was taken at.
+    pub fn slot(&self) -> u64 {
+        self.slot
+    }
+
+    pub fn get_l2(&self, market_id: &MarketId) -> Option<&L2OrderBook> {
+        self.books.get(market_id)
+    }
+
+    pub fn best_bid(&self, market_id: &MarketId) -> Option<i128> {
+        self.books.get(market_id).and_then(L2OrderBook::best_bid)
+    }
+
+    pub fn best_ask(&self, market_id: &MarketId) -> Option<i128> {
+        self.books.get(market_id).and_then(L2OrderBook::best_ask)
+    }
+}
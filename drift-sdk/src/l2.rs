@@ -0,0 +1,182 @@
+#[cfg(test)]
+mod tests;
+
+/// Aggregated size resting at a single price.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct L2Level {
+    pub price: i128,
+    pub size: u64,
+    pub num_orders: u32,
+    /// Size at this level and every better one. Only filled in for cumulative L2 reads,
+    /// 0 otherwise.
+    pub cumulative_size: u64,
+}
+
+/// What `DLOB::get_l2` aggregates. The default is every level, no size filter, no
+/// cumulative sizes and no tick rounding.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct L2Options {
+    /// Most levels kept per side.
+    pub depth: usize,
+    /// Orders with less remaining base than this are left out.
+    pub min_size: u64,
+    /// Fill in each level's `cumulative_size`.
+    pub cumulative: bool,
+    /// Snap prices to this tick before grouping (bids down, asks up) so floating orders a
+    /// fraction of a tick apart share a level.
+    pub round_to_tick: Option<u64>,
+}
+
+impl Default for L2Options {
+    fn default() -> Self {
+        Self {
+            depth: usize::MAX,
+            min_size: 0,
+            cumulative: false,
+            round_to_tick: None,
+        }
+    }
+}
+
+/// Price levels for both sides of a market, best price first.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct L2OrderBook {
+    pub bids: Vec<L2Level>,
+    pub asks: Vec<L2Level>,
+}
+
+impl L2OrderBook {
+    pub fn best_bid(&self) -> Option<i128> {
+        self.bids.first().map(|level| level.price)
+    }
+
+    pub fn best_ask(&self) -> Option<i128> {
+        self.asks.first().map(|level| level.price)
+    }
+
+    /// Compact binary encoding for broadcasting, e.g. in websocket frames. Each side is its
+    /// level count followed by its levels, with prices as zigzag deltas from the previous
+    /// level and every field as a LEB128 varint.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for levels in [&self.bids, &self.asks] {
+            write_varint(&mut bytes, levels.len() as u128);
+            let mut previous_price = 0_i128;
+            for level in levels.iter() {
+                write_varint(&mut bytes, zigzag(level.price.wrapping_sub(previous_price)));
+                write_varint(&mut bytes, level.size as u128);
+                write_varint(&mut bytes, level.num_orders as u128);
+                write_varint(&mut bytes, level.cumulative_size as u128);
+                previous_price = level.price;
+            }
+        }
+        bytes
+    }
+
+    /// Decodes `to_bytes` output. `None` if `bytes` is truncated, has a field out of range
+    /// or has anything left over.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut reader = bytes;
+        let mut sides = Vec::with_capacity(2);
+        for _ in 0..2 {
+            let num_levels = usize::try_from(read_varint(&mut reader)?).ok()?;
+            // every level takes at least four bytes, so a bogus count can't over-allocate
+            let mut levels = Vec::with_capacity(num_levels.min(reader.len() / 4));
+            let mut previous_price = 0_i128;
+            for _ in 0..num_levels {
+                let price = previous_price.wrapping_add(unzigzag(read_varint(&mut reader)?));
+                levels.push(L2Level {
+                    price,
+                    size: u64::try_from(read_varint(&mut reader)?).ok()?,
+                    num_orders: u32::try_from(read_varint(&mut reader)?).ok()?,
+                    cumulative_size: u64::try_from(read_varint(&mut reader)?).ok()?,
+                });
+                previous_price = price;
+            }
+            sides.push(levels);
+        }
+
+        if !reader.is_empty() {
+            return None;
+        }
+
+        let asks = sides.pop()?;
+        let bids = sides.pop()?;
+        Some(Self { bids, asks })
+    }
+}
+
+fn zigzag(value: i128) -> u128 {
+    ((value << 1) ^ (value >> 127)) as u128
+}
+
+fn unzigzag(value: u128) -> i128 {
+    (value >> 1) as i128 ^ -((value & 1) as i128)
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u128) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Reads a varint off the front of `reader`, advancing it.
+fn read_varint(reader: &mut &[u8]) -> Option<u128> {
+    let mut value = 0_u128;
+    for shift in (0..128).step_by(7) {
+        let (&byte, rest) = reader.split_first()?;
+        *reader = rest;
+        let bits = (byte & 0x7f) as u128;
+        // the last of 19 bytes only has room for 2 more bits
+        if shift == 126 && bits > 0b11 {
+            return None;
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Buckets `(price, size)` pairs that are already sorted best price first into levels,
+/// keeping at most `depth` of them.
+pub fn aggregate_levels(
+    orders: impl IntoIterator<Item = (i128, u64)>,
+    depth: usize,
+) -> Vec<L2Level> {
+    let mut levels: Vec<L2Level> = Vec::new();
+
+    for (price, size) in orders {
+        match levels.last_mut() {
+            Some(level) if level.price == price => {
+                level.size = level.size.saturating_add(size);
+                level.num_orders += 1;
+            }
+            _ => {
+                if levels.len() == depth {
+                    break;
+                }
+                levels.push(L2Level {
+                    price,
+                    size,
+                    num_orders: 1,
+                    cumulative_size: 0,
+                });
+            }
+        }
+    }
+
+    levels
+}
+
+/// Fills in `cumulative_size` for levels sorted best price first.
+pub fn accumulate_levels(levels: &mut [L2Level]) {
+    let mut cumulative_size = 0_u64;
+    for level in levels {
+        cumulative_size = cumulative_size.saturating_add(level.size);
+        level.cumulative_size = cumulative_size;
+    }
+}
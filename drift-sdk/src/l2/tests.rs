@@ -0,0 +1,56 @@
+use drift::math::constants::{BASE_PRECISION_U64, PRICE_PRECISION_U64};
+
+use crate::l2::{L2Level, L2OrderBook};
+
+fn level(price: u64, size: u64, num_orders: u32) -> L2Level {
+    L2Level {
+        price: price as i128,
+        size,
+        num_orders,
+        cumulative_size: 0,
+    }
+}
+
+#[test]
+fn wire_format_round_trips() {
+    let l2 = L2OrderBook {
+        bids: vec![
+            level(100 * PRICE_PRECISION_U64, BASE_PRECISION_U64, 1),
+            level(
+                99 * PRICE_PRECISION_U64 + 999_999,
+                3 * BASE_PRECISION_U64,
+                2,
+            ),
+            level(1, u64::MAX, u32::MAX),
+        ],
+        asks: vec![
+            level(101 * PRICE_PRECISION_U64, BASE_PRECISION_U64 / 10, 1),
+            L2Level {
+                price: i128::MAX,
+                cumulative_size: 7,
+                ..level(0, 0, 0)
+            },
+        ],
+    };
+
+    let bytes = l2.to_bytes();
+    assert_eq!(L2OrderBook::from_bytes(&bytes), Some(l2.clone()));
+
+    // neighbouring levels cost a few bytes each rather than full-width fields
+    let near_levels = L2OrderBook {
+        bids: (0..10)
+            .map(|i| level(100 * PRICE_PRECISION_U64 - i * 1_000, BASE_PRECISION_U64, 1))
+            .collect(),
+        asks: vec![],
+    };
+    assert!(near_levels.to_bytes().len() < 10 * 12);
+
+    assert_eq!(
+        L2OrderBook::from_bytes(&L2OrderBook::default().to_bytes()),
+        Some(L2OrderBook::default())
+    );
+    assert_eq!(L2OrderBook::from_bytes(&bytes[..bytes.len() - 1]), None);
+    let mut trailing = bytes;
+    trailing.push(0);
+    assert_eq!(L2OrderBook::from_bytes(&trailing), None);
+}
@@ -0,0 +1,18 @@
+use anchor_lang::prelude::Pubkey;
+
+/// A single resting order as it sits in the book.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct L3Order {
+    pub price: i128,
+    /// Remaining (unfilled) base.
+    pub size: u64,
+    pub maker: Pubkey,
+    pub order_id: u32,
+}
+
+/// Individual orders for both sides of a market, in queue order.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct L3OrderBook {
+    pub bids: Vec<L3Order>,
+    pub asks: Vec<L3Order>,
+}
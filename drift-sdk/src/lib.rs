@@ -1,8 +1,16 @@
+#[macro_use]
+mod macros;
+
 pub mod conversion;
 pub mod dlob;
 pub mod dlob_node;
 pub mod dlob_orders;
+pub mod frozen_dlob;
+pub mod l2;
+pub mod l3;
 pub mod node_list;
+#[cfg(test)]
+mod test_utils;
 
 pub mod math {
     pub use drift::math;
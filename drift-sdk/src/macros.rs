@@ -0,0 +1,8 @@
+/// Emits a `tracing` debug event when the `tracing` feature is enabled and compiles to
+/// nothing otherwise, so the arguments are never evaluated in default builds.
+macro_rules! dlob_trace {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)+);
+    };
+}
@@ -1,17 +1,28 @@
 use std::sync::Arc;
 use std::{collections::HashMap, sync::Mutex};
 
-use anchor_lang::prelude::Pubkey;
-use drift::error::DriftResult;
+use anchor_lang::prelude::{msg, Pubkey};
+use drift::error::{DriftResult, ErrorCode};
+use drift::state::oracle::OraclePriceData;
 use drift::state::user::{Order, OrderStatus};
+use drift::validate;
 
-use crate::dlob_node::{create_node, DLOBNode, DLOBNodeType};
+use crate::dlob_node::{DLOBNode, DLOBNodeType, DefaultNodeFactory, NodeFactory};
+
+#[cfg(test)]
+mod tests;
 
 pub fn get_order_signature(order_id: u32, user_account: &Pubkey) -> String {
     format!("{}-{}", user_account, order_id)
 }
 
-#[derive(Debug, Clone)]
+/// Queue priority between nodes with the same sort value: older slot, then lower order id.
+fn queue_key(node: &Arc<dyn DLOBNode>) -> (u64, u32) {
+    node.order()
+        .map_or((u64::MAX, u32::MAX), |order| (order.slot, order.order_id))
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum SortDirection {
     Asc,
     Desc,
@@ -24,28 +35,79 @@ pub struct NodeWrapper {
     previous: Mutex<Option<Arc<NodeWrapper>>>,
 }
 
-#[derive(Debug, Clone)]
+impl NodeWrapper {
+    pub fn node(&self) -> &Arc<dyn DLOBNode> {
+        &self.node
+    }
+}
+
+#[derive(Debug)]
 pub struct NodeList {
     head: Option<Arc<NodeWrapper>>,
+    tail: Option<Arc<NodeWrapper>>,
     node_type: DLOBNodeType,
     length: usize,
-    node_map: HashMap<String, Arc<dyn DLOBNode>>,
+    node_map: HashMap<String, Arc<NodeWrapper>>,
     sort_direction: SortDirection,
+    node_factory: Arc<dyn NodeFactory>,
+}
+
+impl Clone for NodeList {
+    /// Copies the links along with the list, so removing from a clone can't rewire the
+    /// original. The nodes themselves are shared.
+    fn clone(&self) -> Self {
+        let mut node_list = Self::with_factory(
+            self.node_type.clone(),
+            self.sort_direction,
+            self.node_factory.clone(),
+        );
+        node_list.relink(self.iter().collect());
+        node_list
+    }
+}
+
+impl Drop for NodeList {
+    fn drop(&mut self) {
+        // `previous` closes the reference cycle between neighbours; the forward links are
+        // kept so an iterator still walking the list isn't cut short
+        let mut current = self.head.clone();
+        while let Some(wrapper) = current {
+            *wrapper.previous.lock().unwrap() = None;
+            current = wrapper.next.lock().unwrap().clone();
+        }
+    }
 }
 
 impl NodeList {
     pub fn new(node_type: DLOBNodeType, sort_direction: SortDirection) -> Self {
+        Self::with_factory(node_type, sort_direction, Arc::new(DefaultNodeFactory))
+    }
+
+    pub fn with_factory(
+        node_type: DLOBNodeType,
+        sort_direction: SortDirection,
+        node_factory: Arc<dyn NodeFactory>,
+    ) -> Self {
         Self {
             head: None,
+            tail: None,
             node_type,
             length: 0,
             node_map: HashMap::new(),
             sort_direction,
+            node_factory,
         }
     }
 
     pub fn clear(&mut self) {
-        self.head = None;
+        // neighbouring wrappers hold each other through `next` and `previous`, so the links
+        // have to be broken or the cleared nodes are never freed
+        let mut current = self.head.take();
+        while let Some(wrapper) = current {
+            *wrapper.previous.lock().unwrap() = None;
+            current = wrapper.next.lock().unwrap().take();
+        }
+        self.tail = None;
         self.length = 0;
         self.node_map.clear();
     }
@@ -55,28 +117,46 @@ impl NodeList {
             return Ok(());
         }
 
-        let new_node = Arc::new(NodeWrapper {
-            node: create_node(self.node_type.clone(), order, user_account),
-            next: Mutex::new(None),
-            previous: Mutex::new(None),
-        });
-
         let order_signature = get_order_signature(order.order_id, &user_account);
 
         if self.node_map.contains_key(&order_signature) {
             return Ok(());
         }
 
-        self.node_map
-            .insert(order_signature.clone(), new_node.node.clone());
+        let node = self
+            .node_factory
+            .create_node(self.node_type.clone(), order, user_account);
+        self.link_sorted(order_signature, node)
+    }
+
+    /// Links `node` in at its sorted position and maps it under `order_signature`.
+    fn link_sorted(&mut self, order_signature: String, node: Arc<dyn DLOBNode>) -> DriftResult<()> {
+        let new_node = Arc::new(NodeWrapper {
+            node,
+            next: Mutex::new(None),
+            previous: Mutex::new(None),
+        });
+
+        self.node_map.insert(order_signature, new_node.clone());
         self.length += 1;
 
         if self.head.is_none() {
             self.head = Some(new_node.clone());
+            self.tail = Some(new_node.clone());
             return Ok(());
         }
 
+        if let Some(head) = self.head.clone() {
+            if self.prepend_node(&head.node, &new_node.node)? {
+                *new_node.next.lock().unwrap() = Some(head.clone());
+                *head.previous.lock().unwrap() = Some(new_node.clone());
+                self.head = Some(new_node);
+                return Ok(());
+            }
+        }
+
         let mut current_node = self.head.clone();
+        let mut last_node = None;
 
         while let Some(current) = &current_node.clone() {
             let should_prepend = current
@@ -97,12 +177,14 @@ impl NodeList {
                 return Ok(());
             }
 
+            last_node = Some(current.clone());
             current_node = current.next.lock().unwrap().clone();
         }
 
-        if let Some(last_node) = &current_node {
+        if let Some(last_node) = &last_node {
             *new_node.previous.lock().unwrap() = Some(last_node.clone());
             *last_node.next.lock().unwrap() = Some(new_node.clone());
+            self.tail = Some(new_node.clone());
         }
 
         Ok(())
@@ -116,6 +198,12 @@ impl NodeList {
         let current_order_sort_price = current_node.sort_value();
         let new_order_sort_price = new_node.sort_value();
 
+        // price-time priority: at the same sort value the older order, then the lower
+        // order id, goes first
+        if new_order_sort_price == current_order_sort_price {
+            return Ok(queue_key(new_node) < queue_key(current_node));
+        }
+
         let dir = match self.sort_direction {
             SortDirection::Asc => new_order_sort_price < current_order_sort_price,
             SortDirection::Desc => new_order_sort_price > current_order_sort_price,
@@ -124,31 +212,192 @@ impl NodeList {
         Ok(dir)
     }
 
-    pub fn update(&mut self, order: Order, user_account: Pubkey) -> DriftResult<()> {
+    /// How many nodes would stay ahead of `order` if it were inserted now.
+    pub fn insert_position(&self, order: Order, user_account: Pubkey) -> DriftResult<usize> {
+        let new_node = self
+            .node_factory
+            .create_node(self.node_type.clone(), order, user_account);
+
+        let mut position = 0;
+        for node in self.iter() {
+            if self.prepend_node(&node, &new_node)? {
+                break;
+            }
+            position += 1;
+        }
+
+        Ok(position)
+    }
+
+    /// Replaces the stored node for `order`, returning whether anything actually changed.
+    /// The node keeps its place unless the update changes its sort value.
+    pub fn update(&mut self, order: Order, user_account: Pubkey) -> DriftResult<bool> {
         let order_signature = get_order_signature(order.order_id, &user_account);
-        if self.node_map.contains_key(&order_signature) {
-            let new_node = create_node(self.node_type.clone(), order, user_account);
-            self.node_map.insert(order_signature, new_node);
+        match self.node_map.get(&order_signature).cloned() {
+            Some(wrapper) if wrapper.node.same_state(&order, &user_account) => Ok(false),
+            Some(wrapper) => {
+                let new_node =
+                    self.node_factory
+                        .create_node(self.node_type.clone(), order, user_account);
+                if new_node.sort_value() == wrapper.node.sort_value() {
+                    self.replace_linked_node(order_signature, &wrapper, new_node);
+                } else {
+                    // e.g. a new price: the order has to move to keep the list sorted
+                    self.unlink(&wrapper);
+                    self.node_map.remove(&order_signature);
+                    self.length -= 1;
+                    self.link_sorted(order_signature, new_node)?;
+                }
+                Ok(true)
+            }
+            None => Ok(false),
         }
+    }
 
-        Ok(())
+    /// Swaps `current` for a wrapper holding `node` at the same position, so iteration sees
+    /// the new state without a re-sort.
+    fn replace_linked_node(
+        &mut self,
+        order_signature: String,
+        current: &Arc<NodeWrapper>,
+        node: Arc<dyn DLOBNode>,
+    ) {
+        let previous = current.previous.lock().unwrap().clone();
+        let next = current.next.lock().unwrap().clone();
+        let new_node = Arc::new(NodeWrapper {
+            node,
+            next: Mutex::new(next.clone()),
+            previous: Mutex::new(previous.clone()),
+        });
+
+        match &previous {
+            Some(previous) => *previous.next.lock().unwrap() = Some(new_node.clone()),
+            None => self.head = Some(new_node.clone()),
+        }
+        match &next {
+            Some(next) => *next.previous.lock().unwrap() = Some(new_node.clone()),
+            None => self.tail = Some(new_node.clone()),
+        }
+
+        self.node_map.insert(order_signature, new_node);
+    }
+
+    /// Splices `current` out of the linked list.
+    fn unlink(&mut self, current: &Arc<NodeWrapper>) {
+        let previous = current.previous.lock().unwrap().take();
+        let next = current.next.lock().unwrap().take();
+
+        match &previous {
+            Some(previous) => *previous.next.lock().unwrap() = next.clone(),
+            None => self.head = next.clone(),
+        }
+        match &next {
+            Some(next) => *next.previous.lock().unwrap() = previous,
+            None => self.tail = previous,
+        }
     }
 
     pub fn remove(&mut self, order: Order, user_account: Pubkey) -> DriftResult<()> {
         let order_signature = get_order_signature(order.order_id, &user_account);
-        self.node_map.remove(&order_signature);
+        let wrapper = match self.node_map.remove(&order_signature) {
+            Some(wrapper) => wrapper,
+            None => return Ok(()),
+        };
+        self.unlink(&wrapper);
         self.length -= 1;
 
         Ok(())
     }
 
+    /// Keeps only the nodes for which `f` returns true, preserving their order, and
+    /// returns how many were dropped. Relinks the list in a single pass.
+    pub fn retain<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&Arc<dyn DLOBNode>) -> bool,
+    {
+        let kept: Vec<Arc<dyn DLOBNode>> = self.iter().filter(|node| f(node)).collect();
+        let removed = self.length.saturating_sub(kept.len());
+        if removed == 0 {
+            return 0;
+        }
+
+        self.relink(kept);
+
+        removed
+    }
+
+    /// Re-links the list by each node's `OrderNode::sequence` (then order id). Inserts into
+    /// the sequence-sorted market lists already land in FIFO order, so this only repairs a
+    /// list whose links have gone stale. Nodes without an `OrderNode` keep their relative
+    /// position at the front.
+    pub fn resort_by_sequence(&mut self) {
+        let mut nodes: Vec<Arc<dyn DLOBNode>> = self.iter().collect();
+        nodes.sort_by_key(|node| {
+            node.as_order_node()
+                .map(|order_node| (order_node.sequence, order_node.order.order_id))
+        });
+        self.relink(nodes);
+    }
+
+    /// Replaces the list's contents with `nodes`, linked in the given order.
+    fn relink(&mut self, nodes: Vec<Arc<dyn DLOBNode>>) {
+        self.clear();
+
+        let mut previous: Option<Arc<NodeWrapper>> = None;
+        for node in nodes {
+            let order_signature = match (node.order(), node.user_account()) {
+                (Some(order), Some(user_account)) => {
+                    Some(get_order_signature(order.order_id, user_account))
+                }
+                _ => None,
+            };
+
+            let wrapper = Arc::new(NodeWrapper {
+                node,
+                next: Mutex::new(None),
+                previous: Mutex::new(previous.clone()),
+            });
+            if let Some(order_signature) = order_signature {
+                self.node_map.insert(order_signature, wrapper.clone());
+            }
+            match &previous {
+                Some(previous) => *previous.next.lock().unwrap() = Some(wrapper.clone()),
+                None => self.head = Some(wrapper.clone()),
+            }
+            self.length += 1;
+            previous = Some(wrapper);
+        }
+        self.tail = previous;
+    }
+
     pub fn has(&self, order: Order, user_account: Pubkey) -> DriftResult<bool> {
         let order_signature = get_order_signature(order.order_id, &user_account);
         Ok(self.node_map.contains_key(&order_signature))
     }
 
+    /// Number of orders in the list.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
     pub fn get(&self, order_signature: &str) -> Option<&Arc<dyn DLOBNode>> {
-        self.node_map.get(order_signature)
+        self.node_map
+            .get(order_signature)
+            .map(|wrapper| wrapper.node())
+    }
+
+    /// Signatures of every order currently in the list, in no particular order.
+    pub fn signatures(&self) -> impl Iterator<Item = &str> {
+        self.node_map.keys().map(String::as_str)
+    }
+
+    /// Last node in sort order, i.e. the worst priced order on the list.
+    pub fn tail(&self) -> Option<Arc<dyn DLOBNode>> {
+        self.tail.as_ref().map(|tail| tail.node.clone())
     }
 
     pub fn iter(&self) -> NodeListIter {
@@ -157,12 +406,112 @@ impl NodeList {
         }
     }
 
+    /// Up to `limit` nodes starting `offset` nodes from the head, in sort order.
+    pub fn page(&self, offset: usize, limit: usize) -> Vec<Arc<dyn DLOBNode>> {
+        self.iter().skip(offset).take(limit).collect()
+    }
+
+    /// Walks the list in sort order, stopping as soon as `f` returns false.
+    pub fn for_each_until<F>(&self, mut f: F)
+    where
+        F: FnMut(&Arc<dyn DLOBNode>) -> bool,
+    {
+        for node in self.iter() {
+            if !f(&node) {
+                break;
+            }
+        }
+    }
+
+    /// Iterates the list in sort order alongside each node's effective price.
+    pub fn iter_priced<'a>(
+        &self,
+        oracle_price_data: &'a OraclePriceData,
+        slot: u64,
+    ) -> impl Iterator<Item = (i128, Arc<dyn DLOBNode>)> + 'a {
+        self.iter()
+            .map(move |node| (node.get_price(oracle_price_data, slot), node))
+    }
+
+    /// Signatures of nodes still linked into the list but missing from `node_map`.
+    pub fn orphan_signatures(&self) -> Vec<String> {
+        self.iter()
+            .filter_map(|node| {
+                Some(get_order_signature(
+                    node.order()?.order_id,
+                    node.user_account()?,
+                ))
+            })
+            .filter(|order_signature| !self.node_map.contains_key(order_signature))
+            .collect()
+    }
+
+    /// Checks that the linked list, `node_map` and `length` agree and that adjacent nodes
+    /// are ordered per the list's `sort_direction`.
+    pub fn validate(&self) -> DriftResult<()> {
+        let mut count = 0_usize;
+        let mut previous_sort_value: Option<i128> = None;
+
+        for node in self.iter() {
+            count += 1;
+
+            if let (Some(order), Some(user_account)) = (node.order(), node.user_account()) {
+                let order_signature = get_order_signature(order.order_id, user_account);
+                validate!(
+                    self.node_map.contains_key(&order_signature),
+                    ErrorCode::DefaultError,
+                    "{:?} list links {} but it is missing from node_map",
+                    self.node_type,
+                    order_signature
+                )?;
+            }
+
+            let sort_value = node.sort_value();
+            if let Some(previous_sort_value) = previous_sort_value {
+                let in_order = match self.sort_direction {
+                    SortDirection::Asc => previous_sort_value <= sort_value,
+                    SortDirection::Desc => previous_sort_value >= sort_value,
+                };
+                validate!(
+                    in_order,
+                    ErrorCode::DefaultError,
+                    "{:?} list is not sorted {:?}: {} is followed by {}",
+                    self.node_type,
+                    self.sort_direction,
+                    previous_sort_value,
+                    sort_value
+                )?;
+            }
+            previous_sort_value = Some(sort_value);
+        }
+
+        validate!(
+            count == self.length && count == self.node_map.len(),
+            ErrorCode::DefaultError,
+            "{:?} list links {} nodes but length is {} and node_map has {}",
+            self.node_type,
+            count,
+            self.length,
+            self.node_map.len()
+        )?;
+
+        Ok(())
+    }
+
+    /// Logs every node's label in sort order at debug level.
     pub fn print(&self) {
-        // TODO
+        self.print_top(self.length);
     }
 
-    pub fn print_top(&self) {
-        // TODO
+    /// Logs the first `n` nodes' labels in sort order at debug level.
+    pub fn print_top(&self, n: usize) {
+        log::debug!("{:?} list: {} nodes", self.node_type, self.length);
+        for node in self.iter().take(n) {
+            match node.as_order_node() {
+                Some(order_node) => log::debug!("{}", order_node.get_label()),
+                None => log::debug!("{:?}", node),
+            }
+        }
     }
 }
 
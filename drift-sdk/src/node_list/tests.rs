@@ -0,0 +1,495 @@
+use std::sync::{Arc, Weak};
+
+use anchor_lang::prelude::Pubkey;
+use drift::controller::position::PositionDirection;
+use drift::error::ErrorCode;
+use drift::math::constants::{BASE_PRECISION_U64, PRICE_PRECISION_U64};
+use drift::state::oracle::OraclePriceData;
+use drift::state::user::{Order, OrderStatus, OrderType};
+
+use crate::dlob_node::{DLOBNode, DLOBNodeType};
+use crate::node_list::{get_order_signature, NodeList, NodeWrapper, SortDirection};
+
+/// Checks the tracked length agrees with what iteration actually walks.
+fn assert_len_matches_iter(list: &NodeList) {
+    debug_assert_eq!(list.len(), list.iter().count());
+    debug_assert_eq!(list.is_empty(), list.iter().next().is_none());
+}
+
+fn limit_order(order_id: u32, direction: PositionDirection, price: u64) -> Order {
+    Order {
+        status: OrderStatus::Open,
+        order_type: OrderType::Limit,
+        order_id,
+        direction,
+        price,
+        base_asset_amount: BASE_PRECISION_U64,
+        post_only: true,
+        ..Order::default()
+    }
+}
+
+#[test]
+fn update_detects_unchanged_state() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    let user = Pubkey::new_unique();
+    let order = limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+
+    list.insert(order, user).unwrap();
+
+    assert!(!list.update(order, user).unwrap());
+
+    let filled = Order {
+        base_asset_amount_filled: BASE_PRECISION_U64 / 2,
+        ..order
+    };
+    assert!(list.update(filled, user).unwrap());
+    assert!(!list.update(filled, user).unwrap());
+
+    let unknown = limit_order(2, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+    assert!(!list.update(unknown, user).unwrap());
+}
+
+#[test]
+fn update_with_new_price_moves_the_node() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    let user = Pubkey::new_unique();
+    for (order_id, price) in [(1, 102), (2, 101), (3, 100)] {
+        let bid = limit_order(
+            order_id,
+            PositionDirection::Long,
+            price * PRICE_PRECISION_U64,
+        );
+        list.insert(bid, user).unwrap();
+    }
+
+    let repriced = limit_order(3, PositionDirection::Long, 103 * PRICE_PRECISION_U64);
+    assert!(list.update(repriced, user).unwrap());
+
+    let order_ids: Vec<u32> = list
+        .iter()
+        .map(|node| node.order().unwrap().order_id)
+        .collect();
+    assert_eq!(order_ids, vec![3, 1, 2]);
+    assert_eq!(list.tail().unwrap().order().unwrap().order_id, 2);
+    list.validate().unwrap();
+    assert_len_matches_iter(&list);
+}
+
+#[test]
+fn iter_priced_yields_effective_prices() {
+    let user = Pubkey::new_unique();
+    let oracle_price_data = OraclePriceData {
+        price: 100 * PRICE_PRECISION_U64 as i64,
+        ..OraclePriceData::default_usd()
+    };
+
+    let mut fixed = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    fixed
+        .insert(
+            limit_order(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64),
+            user,
+        )
+        .unwrap();
+
+    let mut floating = NodeList::new(DLOBNodeType::FloatingLimit, SortDirection::Desc);
+    floating
+        .insert(
+            Order {
+                oracle_price_offset: -(2 * PRICE_PRECISION_U64 as i32),
+                ..limit_order(2, PositionDirection::Long, 0)
+            },
+            user,
+        )
+        .unwrap();
+
+    let fixed_prices: Vec<i128> = fixed
+        .iter_priced(&oracle_price_data, 0)
+        .map(|(price, _)| price)
+        .collect();
+    assert_eq!(fixed_prices, vec![99 * PRICE_PRECISION_U64 as i128]);
+
+    let floating_prices: Vec<(i128, u32)> = floating
+        .iter_priced(&oracle_price_data, 0)
+        .map(|(price, node)| (price, node.order().unwrap().order_id))
+        .collect();
+    assert_eq!(floating_prices, vec![(98 * PRICE_PRECISION_U64 as i128, 2)]);
+}
+
+#[test]
+fn validate_detects_mis_ordered_list() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    let user = Pubkey::new_unique();
+
+    for (order_id, price) in [(1, 100), (2, 101), (3, 99)] {
+        list.insert(
+            limit_order(
+                order_id,
+                PositionDirection::Long,
+                price * PRICE_PRECISION_U64,
+            ),
+            user,
+        )
+        .unwrap();
+    }
+    assert_eq!(list.validate(), Ok(()));
+
+    // the nodes are linked highest first, which is wrong for an ascending list
+    list.sort_direction = SortDirection::Asc;
+    assert_eq!(list.validate(), Err(ErrorCode::DefaultError));
+}
+
+#[test]
+fn for_each_until_stops_at_first_ask_above_price() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Asc);
+    let user = Pubkey::new_unique();
+    for (order_id, price) in [(1, 100), (2, 101), (3, 102), (4, 103)] {
+        let ask = limit_order(
+            order_id,
+            PositionDirection::Short,
+            price * PRICE_PRECISION_U64,
+        );
+        list.insert(ask, user).unwrap();
+    }
+
+    let mut visited = vec![];
+    let mut crossed = vec![];
+    list.for_each_until(|node| {
+        let order = node.order().unwrap();
+        visited.push(order.order_id);
+        if order.price > 101 * PRICE_PRECISION_U64 {
+            return false;
+        }
+        crossed.push(order.order_id);
+        true
+    });
+
+    assert_eq!(crossed, vec![1, 2]);
+    assert_eq!(visited, vec![1, 2, 3]);
+}
+
+#[test]
+fn resort_by_sequence_restores_fifo_order() {
+    let mut list = NodeList::new(DLOBNodeType::Market, SortDirection::Asc);
+    let user = Pubkey::new_unique();
+    for (order_id, slot) in [(1, 5), (2, 3), (3, 7)] {
+        let market_bid = Order {
+            order_type: OrderType::Market,
+            price: 0,
+            post_only: false,
+            slot,
+            ..limit_order(order_id, PositionDirection::Long, 0)
+        };
+        list.insert(market_bid, user).unwrap();
+    }
+    assert!(list
+        .iter()
+        .all(|node| node.sort_value() == node.order().unwrap().slot as i128));
+
+    let order_ids = |list: &NodeList| -> Vec<u32> {
+        list.iter()
+            .map(|node| node.order().unwrap().order_id)
+            .collect()
+    };
+    assert_eq!(order_ids(&list), vec![2, 1, 3]);
+
+    // link the nodes newest first so the list is out of sequence
+    let mut stale: Vec<Arc<dyn DLOBNode>> = list.iter().collect();
+    stale.reverse();
+    list.relink(stale);
+    assert_eq!(order_ids(&list), vec![3, 1, 2]);
+
+    list.resort_by_sequence();
+
+    assert_eq!(order_ids(&list), vec![2, 1, 3]);
+    assert_eq!(list.tail().unwrap().order().unwrap().order_id, 3);
+    list.validate().unwrap();
+}
+
+#[test]
+fn retain_and_clear_free_the_unlinked_wrappers() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    let user = Pubkey::new_unique();
+    for order_id in 1..=3 {
+        let bid = limit_order(order_id, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+        list.insert(bid, user).unwrap();
+    }
+    let wrappers = |list: &NodeList| -> Vec<Weak<NodeWrapper>> {
+        list.node_map.values().map(Arc::downgrade).collect()
+    };
+
+    let before_retain = wrappers(&list);
+    list.retain(|node| node.order().unwrap().order_id != 2);
+    assert!(before_retain
+        .iter()
+        .all(|wrapper| wrapper.upgrade().is_none()));
+
+    let before_clear = wrappers(&list);
+    assert_eq!(before_clear.len(), 2);
+    list.clear();
+    assert!(before_clear
+        .iter()
+        .all(|wrapper| wrapper.upgrade().is_none()));
+}
+
+#[test]
+fn orphan_signatures_finds_unmapped_nodes() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    let user = Pubkey::new_unique();
+    for order_id in 1..=3 {
+        let bid = limit_order(order_id, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+        list.insert(bid, user).unwrap();
+    }
+    assert!(list.orphan_signatures().is_empty());
+
+    let orphan = get_order_signature(2, &user);
+    list.node_map.remove(&orphan);
+
+    assert_eq!(list.orphan_signatures(), vec![orphan]);
+}
+
+#[test]
+fn remove_missing_order_leaves_length_alone() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    let user = Pubkey::new_unique();
+    let bid = limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+
+    list.remove(bid, user).unwrap();
+    assert_eq!(list.length, 0);
+    assert_len_matches_iter(&list);
+
+    list.insert(bid, user).unwrap();
+    list.remove(
+        limit_order(2, PositionDirection::Long, 100 * PRICE_PRECISION_U64),
+        user,
+    )
+    .unwrap();
+    assert_eq!(list.length, 1);
+    assert_len_matches_iter(&list);
+}
+
+#[test]
+fn remove_unlinks_middle_node() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    let user = Pubkey::new_unique();
+    let bids: Vec<Order> = [(1, 102), (2, 101), (3, 100)]
+        .into_iter()
+        .map(|(order_id, price)| {
+            limit_order(
+                order_id,
+                PositionDirection::Long,
+                price * PRICE_PRECISION_U64,
+            )
+        })
+        .collect();
+    for bid in &bids {
+        list.insert(*bid, user).unwrap();
+    }
+
+    list.remove(bids[1], user).unwrap();
+
+    let order_ids: Vec<u32> = list
+        .iter()
+        .map(|node| node.order().unwrap().order_id)
+        .collect();
+    assert_eq!(order_ids, vec![1, 3]);
+    list.validate().unwrap();
+    assert_len_matches_iter(&list);
+
+    list.remove(bids[0], user).unwrap();
+    list.remove(bids[2], user).unwrap();
+    assert_eq!(list.iter().count(), 0);
+    assert!(list.tail().is_none());
+    assert_len_matches_iter(&list);
+}
+
+#[test]
+fn removing_from_a_clone_leaves_the_original_linked() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    let user = Pubkey::new_unique();
+    let bids: Vec<Order> = [(1, 102), (2, 101), (3, 100)]
+        .into_iter()
+        .map(|(order_id, price)| {
+            limit_order(
+                order_id,
+                PositionDirection::Long,
+                price * PRICE_PRECISION_U64,
+            )
+        })
+        .collect();
+    for bid in &bids {
+        list.insert(*bid, user).unwrap();
+    }
+
+    let mut copy = list.clone();
+    copy.remove(bids[1], user).unwrap();
+    copy.remove(bids[0], user).unwrap();
+
+    let order_ids = |list: &NodeList| -> Vec<u32> {
+        list.iter()
+            .map(|node| node.order().unwrap().order_id)
+            .collect()
+    };
+    assert_eq!(order_ids(&copy), vec![3]);
+    assert_eq!(order_ids(&list), vec![1, 2, 3]);
+    list.validate().unwrap();
+    assert_len_matches_iter(&list);
+}
+
+#[test]
+fn node_map_and_links_share_wrappers() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    let user = Pubkey::new_unique();
+    let bid = limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64);
+    list.insert(bid, user).unwrap();
+
+    let filled = Order {
+        base_asset_amount_filled: BASE_PRECISION_U64 / 2,
+        ..bid
+    };
+    list.update(filled, user).unwrap();
+
+    let order_signature = get_order_signature(1, &user);
+    let head = list.iter().next().unwrap();
+    assert_eq!(head.order(), Some(&filled));
+    assert_eq!(list.get(&order_signature).unwrap().order(), Some(&filled));
+
+    list.remove(filled, user).unwrap();
+    assert!(list.get(&order_signature).is_none());
+    assert_eq!(list.iter().count(), 0);
+}
+
+#[test]
+fn print_top_logs_header_and_labels() {
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    thread_local! {
+        static LINES: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+    static INIT: Once = Once::new();
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LINES.with(|lines| lines.borrow_mut().push(record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    INIT.call_once(|| {
+        log::set_boxed_logger(Box::new(CaptureLogger)).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+    });
+
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    let user = Pubkey::new_unique();
+    for (order_id, price) in [(1, 102), (2, 101), (3, 100)] {
+        let bid = limit_order(
+            order_id,
+            PositionDirection::Long,
+            price * PRICE_PRECISION_U64,
+        );
+        list.insert(bid, user).unwrap();
+    }
+
+    list.print_top(2);
+
+    let lines = LINES.with(|lines| lines.borrow().clone());
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "RestingLimit list: 3 nodes");
+    assert!(lines[1].starts_with(&format!("Order {}", get_order_signature(1, &user))));
+    assert!(lines[1].ends_with("@ 102.000"));
+    assert!(lines[2].ends_with("@ 101.000"));
+}
+
+#[test]
+fn page_slices_in_sort_order() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Asc);
+    let user = Pubkey::new_unique();
+    for order_id in 1..=10 {
+        let price = order_id as u64 * PRICE_PRECISION_U64;
+        list.insert(limit_order(order_id, PositionDirection::Short, price), user)
+            .unwrap();
+    }
+
+    let pages: Vec<Vec<u32>> = (0..4)
+        .map(|page| {
+            list.page(page * 3, 3)
+                .iter()
+                .map(|node| node.order().unwrap().order_id)
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        pages,
+        vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9], vec![10]]
+    );
+    assert!(list.page(10, 3).is_empty());
+}
+
+#[test]
+fn same_price_orders_queue_oldest_first() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Desc);
+    let user = Pubkey::new_unique();
+    let newer = Order {
+        slot: 20,
+        ..limit_order(1, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+    let older = Order {
+        slot: 10,
+        ..limit_order(2, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+    let same_slot = Order {
+        slot: 10,
+        ..limit_order(3, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+    };
+    let better = limit_order(4, PositionDirection::Long, 101 * PRICE_PRECISION_U64);
+
+    for order in [newer, same_slot, older, better] {
+        list.insert(order, user).unwrap();
+    }
+
+    let order_ids: Vec<u32> = list
+        .iter()
+        .map(|node| node.order().unwrap().order_id)
+        .collect();
+    assert_eq!(order_ids, vec![4, 2, 3, 1]);
+    list.validate().unwrap();
+}
+
+#[test]
+fn len_tracks_inserts_and_removes() {
+    let mut list = NodeList::new(DLOBNodeType::RestingLimit, SortDirection::Asc);
+    let user = Pubkey::new_unique();
+    assert!(list.is_empty());
+
+    let asks: Vec<Order> = (1..=3)
+        .map(|order_id| {
+            limit_order(
+                order_id,
+                PositionDirection::Short,
+                (100 + order_id as u64) * PRICE_PRECISION_U64,
+            )
+        })
+        .collect();
+    for ask in &asks {
+        list.insert(*ask, user).unwrap();
+        assert_len_matches_iter(&list);
+    }
+    // a repeat insert doesn't add a node
+    list.insert(asks[0], user).unwrap();
+    assert_eq!(list.len(), 3);
+
+    for ask in &asks {
+        list.remove(*ask, user).unwrap();
+        assert_len_matches_iter(&list);
+    }
+    assert!(list.is_empty());
+}
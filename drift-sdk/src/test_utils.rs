@@ -0,0 +1,222 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anchor_lang::prelude::{AccountInfo, Pubkey};
+use anchor_lang::{Owner, ZeroCopy};
+use drift::controller::position::PositionDirection;
+use drift::math::constants::BASE_PRECISION_U64;
+use drift::state::oracle::OraclePriceData;
+use drift::state::user::{Order, OrderStatus, OrderTriggerCondition, OrderType};
+
+use crate::dlob::MarketType;
+use crate::dlob_node::{create_node, DLOBNode, DLOBNodeType, NodeFactory, OrderNode};
+
+/// Fluent builder for the `Order` fields the DLOB cares about. Starts from an open,
+/// one-base perp-0 limit bid.
+#[derive(Debug, Clone, Copy)]
+pub struct OrderBuilder {
+    order: Order,
+}
+
+impl Default for OrderBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OrderBuilder {
+    pub fn new() -> Self {
+        Self {
+            order: Order {
+                status: OrderStatus::Open,
+                order_type: OrderType::Limit,
+                market_type: drift::state::user::MarketType::Perp,
+                direction: PositionDirection::Long,
+                base_asset_amount: BASE_PRECISION_U64,
+                ..Order::default()
+            },
+        }
+    }
+
+    pub fn order_id(mut self, order_id: u32) -> Self {
+        self.order.order_id = order_id;
+        self
+    }
+
+    pub fn market(mut self, market_index: u16, market_type: MarketType) -> Self {
+        self.order.market_index = market_index;
+        self.order.market_type = market_type.into();
+        self
+    }
+
+    pub fn direction(mut self, direction: PositionDirection) -> Self {
+        self.order.direction = direction;
+        self
+    }
+
+    pub fn long(self) -> Self {
+        self.direction(PositionDirection::Long)
+    }
+
+    pub fn short(self) -> Self {
+        self.direction(PositionDirection::Short)
+    }
+
+    pub fn order_type(mut self, order_type: OrderType) -> Self {
+        self.order.order_type = order_type;
+        self
+    }
+
+    pub fn status(mut self, status: OrderStatus) -> Self {
+        self.order.status = status;
+        self
+    }
+
+    pub fn price(mut self, price: u64) -> Self {
+        self.order.price = price;
+        self
+    }
+
+    pub fn oracle_price_offset(mut self, oracle_price_offset: i32) -> Self {
+        self.order.oracle_price_offset = oracle_price_offset;
+        self
+    }
+
+    pub fn base(mut self, base_asset_amount: u64) -> Self {
+        self.order.base_asset_amount = base_asset_amount;
+        self
+    }
+
+    pub fn filled(mut self, base_asset_amount_filled: u64) -> Self {
+        self.order.base_asset_amount_filled = base_asset_amount_filled;
+        self
+    }
+
+    pub fn trigger(mut self, trigger_price: u64, trigger_condition: OrderTriggerCondition) -> Self {
+        self.order.trigger_price = trigger_price;
+        self.order.trigger_condition = trigger_condition;
+        self
+    }
+
+    pub fn post_only(mut self, post_only: bool) -> Self {
+        self.order.post_only = post_only;
+        self
+    }
+
+    pub fn auction(mut self, start_price: i64, end_price: i64, duration: u8) -> Self {
+        self.order.auction_start_price = start_price;
+        self.order.auction_end_price = end_price;
+        self.order.auction_duration = duration;
+        self
+    }
+
+    pub fn slot(mut self, slot: u64) -> Self {
+        self.order.slot = slot;
+        self
+    }
+
+    pub fn max_ts(mut self, max_ts: i64) -> Self {
+        self.order.max_ts = max_ts;
+        self
+    }
+
+    pub fn build(self) -> Order {
+        self.order
+    }
+}
+
+/// Discriminator-prefixed account data for a zero-copy anchor account.
+pub fn get_anchor_account_bytes<T: ZeroCopy + Owner>(account: &mut T) -> Vec<u8> {
+    let mut bytes = T::discriminator().to_vec();
+    bytes.extend_from_slice(bytemuck::bytes_of_mut(account));
+    bytes
+}
+
+pub fn create_account_info<'a>(
+    key: &'a Pubkey,
+    lamports: &'a mut u64,
+    bytes: &'a mut [u8],
+    owner: &'a Pubkey,
+) -> AccountInfo<'a> {
+    AccountInfo::new(key, false, true, lamports, bytes, owner, false, 0)
+}
+
+/// Wraps a default node, counting how often it is priced.
+#[derive(Debug)]
+pub struct CountingNode {
+    inner: Arc<dyn DLOBNode>,
+    get_price_calls: Arc<AtomicUsize>,
+}
+
+impl DLOBNode for CountingNode {
+    fn get_price(&self, oracle_price_data: &OraclePriceData, slot: u64) -> i128 {
+        self.get_price_calls.fetch_add(1, Ordering::SeqCst);
+        self.inner.get_price(oracle_price_data, slot)
+    }
+
+    fn is_vamm_node(&self) -> bool {
+        self.inner.is_vamm_node()
+    }
+
+    fn is_base_filled(&self) -> bool {
+        self.inner.is_base_filled()
+    }
+
+    fn have_filled(&self) -> bool {
+        self.inner.have_filled()
+    }
+
+    fn order(&self) -> Option<&Order> {
+        self.inner.order()
+    }
+
+    fn user_account(&self) -> Option<&Pubkey> {
+        self.inner.user_account()
+    }
+
+    fn sort_value(&self) -> i128 {
+        self.inner.sort_value()
+    }
+
+    fn as_order_node(&self) -> Option<&OrderNode> {
+        self.inner.as_order_node()
+    }
+}
+
+/// Node factory building `CountingNode`s and counting how many nodes it creates and how
+/// often they are priced.
+#[derive(Debug, Default)]
+pub struct CountingNodeFactory {
+    created: AtomicUsize,
+    get_price_calls: Arc<AtomicUsize>,
+}
+
+impl CountingNodeFactory {
+    pub fn created(&self) -> usize {
+        self.created.load(Ordering::SeqCst)
+    }
+
+    pub fn get_price_calls(&self) -> usize {
+        self.get_price_calls.load(Ordering::SeqCst)
+    }
+
+    pub fn reset(&self) {
+        self.created.store(0, Ordering::SeqCst);
+        self.get_price_calls.store(0, Ordering::SeqCst);
+    }
+}
+
+impl NodeFactory for CountingNodeFactory {
+    fn create_node(
+        &self,
+        node_type: DLOBNodeType,
+        order: Order,
+        user_account: Pubkey,
+    ) -> Arc<dyn DLOBNode> {
+        self.created.fetch_add(1, Ordering::SeqCst);
+        Arc::new(CountingNode {
+            inner: create_node(node_type, order, user_account),
+            get_price_calls: self.get_price_calls.clone(),
+        })
+    }
+}